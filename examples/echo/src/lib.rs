@@ -2,13 +2,13 @@
 extern crate plumber_rs;
 
 use plumber_rs::servlet::{SyncServlet, BootstrapResult, ServletFuncResult, Bootstrap, Unimplemented};
-use plumber_rs::pipe::{Pipe, PIPE_INPUT, PIPE_OUTPUT, PIPE_PERSIST};
+use plumber_rs::pipe::{Pipe, Input, Output, PIPE_INPUT, PIPE_OUTPUT, PIPE_PERSIST};
 
 use std::io::{BufRead, Write};
 
 struct Servlet {
-    input : Pipe<i32>,
-    output: Pipe<()>
+    input : Pipe<Input, i32>,
+    output: Pipe<Output>
 }
 
 impl SyncServlet for Servlet {
@@ -24,8 +24,6 @@ impl SyncServlet for Servlet {
     { 
         let mut reader = self.input.as_bufreader();
         let mut line = String::new();
-        let state = self.input.get_state();
-        let mut new_state = Box::new(*state.unwrap_or(&0));
 
         while let Ok(size) = reader.read_line(&mut line)
         {
@@ -35,23 +33,25 @@ impl SyncServlet for Servlet {
                 {
                     Some(false) => {
                         self.input.set_flags(PIPE_PERSIST);
-                        self.input.push_state(new_state);
                         return Ok(());
                     },
                     Some(true) => {
-                        self.input.clear_flags(PIPE_PERSIST);
+                        self.input.take_state();
                         return Ok(());
                     },
                     _ => {
-                        self.input.clear_flags(PIPE_PERSIST);
+                        self.input.take_state();
                         return Err(());
                     }
                 }
             }
             else
             {
-                *(new_state.as_mut()) += 1;
-                write!(self.output, "{} {}", new_state.as_ref(), line);
+                if let Some(mut count) = self.input.state_mut_or_insert_with(|| 0)
+                {
+                    *count += 1;
+                    write!(self.output, "{} {}", *count, line);
+                }
             }
         }
 
@@ -1,8 +1,8 @@
 #[macro_use]
 extern crate plumber_rs;
 
-use plumber_rs::servlet::{SyncServlet, ServletFuncResult, Bootstrap, BootstrapResult, Unimplemented, success, fail};
-use plumber_rs::pipe::{Pipe, PIPE_INPUT, PIPE_OUTPUT};
+use plumber_rs::servlet::{SyncServlet, ServletFuncResult, Bootstrap, BootstrapResult, Unimplemented, success};
+use plumber_rs::pipe::{Pipe, PipeSet, Input, Output, PIPE_INPUT, PIPE_OUTPUT};
 use plumber_rs::protocol::ProtocolModel;
 
 //use std::io::Write;
@@ -15,9 +15,15 @@ protodef! {
     }
 }
 
+pipe_set! {
+    pipes ServletPipes {
+        "input"  [PIPE_INPUT]  : "graphics/Point2D" => input  : Pipe<Input>;
+        "output" [PIPE_OUTPUT] : "float"            => output : Pipe<Output>;
+    }
+}
+
 struct Servlet {
-    input : Pipe<()>,
-    output: Pipe<()>
+    pipes : ServletPipes
 }
 
 impl SyncServlet for Servlet {
@@ -26,25 +32,15 @@ impl SyncServlet for Servlet {
 
     fn init(&mut self, _args:&[&str], model : &mut Self::ProtocolType) -> ServletFuncResult 
     {
-        init_protocol!{
-            model {
-                self.input => input,
-                self.output => output
-            }
-        }
+        init_protocol!(model from self.pipes);
         return success();
     }
     fn exec(&mut self, mut model : Self::DataModelType) -> ServletFuncResult 
     { 
-        if let Some(x) = model.x_coord().get()
-        {
-            if let Some(y) = model.y_coord().get()
-            {
-                model.distance().set((x*x + y*y).sqrt());
-                return success();
-            }
-        }
-        return fail();
+        let x = model.x_coord().try_get().map_err(|err| { plumber_log!(E "{}", err); })?;
+        let y = model.y_coord().try_get().map_err(|err| { plumber_log!(E "{}", err); })?;
+        model.distance().try_set((x*x + y*y).sqrt()).map_err(|err| { plumber_log!(E "{}", err); })?;
+        return success();
     }
     fn cleanup(&mut self) -> ServletFuncResult { success() }
 }
@@ -56,17 +52,13 @@ impl Bootstrap for BootstrapType {
     type AsyncServletType = Unimplemented;
     fn get(_args:&[&str]) -> BootstrapResult<Self>
     {
-        if let Some(input) = Pipe::define("input", PIPE_INPUT, Some("graphics/Point2D"))
-        {
-            if let Some(output) = Pipe::define("output", PIPE_OUTPUT, Some("float"))
-            {
-                return Self::make_sync(Servlet{
-                    input : input,
-                    output: output
-                });
+        match ServletPipes::define_all() {
+            Ok(pipes) => return Self::make_sync(Servlet{ pipes : pipes }),
+            Err(err)  => {
+                plumber_log!(E "{}", err);
+                return Self::fail();
             }
         }
-        return Self::fail();
     }
 }
 
@@ -0,0 +1,201 @@
+// Copyright (C) 2018, Hao Hou
+
+//! The library configuration access.
+//!
+//! Plumber allows the operators to tune the libraries at runtime with the PSS code, for example:
+//!
+//! ```text
+//!     plumber.std.libconf.my_servlet.cache_size = 4096;
+//! ```
+//!
+//! This module reads those configurations. Besides reading a single key with `get_numeric` and
+//! `get_string`, a typed config struct can be declared with the `servlet_config!` macro, and all
+//! the keys are read under a servlet-specific prefix:
+//!
+//! ```rust
+//!     servlet_config! {
+//!         config CacheConfig {
+//!             "cache_size" : usize = 4096 => cache_size;
+//!             "cache_name" : String = "default".to_string() => cache_name;
+//!         }
+//!     }
+//!
+//!     // Reads my_servlet.cache_size and my_servlet.cache_name
+//!     let config = CacheConfig::load("my_servlet");
+//! ```
+
+use crate::pstd::{
+    pstd_libconf_read_numeric,
+    pstd_libconf_read_string
+};
+
+use std::ffi::{CString, CStr};
+use std::convert::TryFrom;
+
+/**
+ * Read a numeric library configuration
+ *
+ * * `key`: The configuration key
+ * * `default`: The default value used when the key isn't set
+ *
+ * Returns the configuration value
+ **/
+pub fn get_numeric(key:&str, default:i64) -> i64
+{
+    if let Ok(c_key) = CString::new(key)
+    {
+        return unsafe { pstd_libconf_read_numeric(c_key.as_ptr(), default) };
+    }
+    return default;
+}
+
+/**
+ * Read a string library configuration
+ *
+ * * `key`: The configuration key
+ * * `default`: The default value used when the key isn't set
+ *
+ * Returns the configuration value
+ **/
+pub fn get_string(key:&str, default:&str) -> String
+{
+    if let (Ok(c_key), Ok(c_default)) = (CString::new(key), CString::new(default))
+    {
+        let result = unsafe { pstd_libconf_read_string(c_key.as_ptr(), c_default.as_ptr()) };
+
+        if !result.is_null()
+        {
+            if let Ok(value) = unsafe { CStr::from_ptr(result) }.to_str()
+            {
+                return value.to_string();
+            }
+        }
+    }
+    return default.to_string();
+}
+
+/**
+ * The trait for a Rust type that can be read from the library configuration
+ **/
+pub trait ConfigValueType : Sized {
+    /**
+     * Read the value from the library configuration
+     *
+     * * `key`: The configuration key
+     * * `default`: The default value used when the key isn't set or the value is invalid
+     *
+     * Returns the configuration value
+     **/
+    fn read_config(key:&str, default:Self) -> Self;
+}
+
+impl ConfigValueType for String {
+    fn read_config(key:&str, default:String) -> String
+    {
+        return get_string(key, &default);
+    }
+}
+
+impl ConfigValueType for bool {
+    fn read_config(key:&str, default:bool) -> bool
+    {
+        return get_numeric(key, default as i64) != 0;
+    }
+}
+
+macro_rules! integer_config_type {
+    ($($type:ty),*) => {
+        $(impl ConfigValueType for $type {
+            fn read_config(key:&str, default:$type) -> $type
+            {
+                // A default that doesn't fit in the libconf numeric can't be passed to libconf, so
+                // the value is read as a string and parsed the same way as the floating points
+                return match i64::try_from(default).ok() {
+                    Some(c_default) => <$type>::try_from(get_numeric(key, c_default)).unwrap_or(default),
+                    None            => get_string(key, &default.to_string()).parse().unwrap_or(default)
+                };
+            }
+        })*
+    }
+}
+
+integer_config_type!(i8, i16, i32, i64, u8, u16, u32, u64, usize, isize);
+
+macro_rules! float_config_type {
+    ($($type:ty),*) => {
+        $(impl ConfigValueType for $type {
+            fn read_config(key:&str, default:$type) -> $type
+            {
+                return get_string(key, &default.to_string()).parse().unwrap_or(default);
+            }
+        })*
+    }
+}
+
+float_config_type!(f32, f64);
+
+/**
+ * Make the full configuration key under the prefix
+ *
+ * * `prefix`: The prefix, an empty prefix means the key is used as it is
+ * * `key`: The key under the prefix
+ *
+ * Returns the full key
+ **/
+pub fn prefixed_key(prefix:&str, key:&str) -> String
+{
+    if prefix.is_empty()
+    {
+        return key.to_string();
+    }
+    return format!("{}.{}", prefix, key);
+}
+
+/**
+ * The trait for a typed config struct, which is usually implemented by the macro
+ * `servlet_config!`
+ **/
+pub trait ServletConfig : Sized {
+    /**
+     * Load the config struct from the library configuration
+     *
+     * * `prefix`: The prefix of all the keys, for example, the name of the servlet
+     *
+     * Returns the loaded config
+     **/
+    fn load(prefix:&str) -> Self;
+}
+
+/**
+ * Declare a typed config struct which is loaded from the library configuration.
+ *
+ * The syntax of each field is:
+ *
+ * ```
+ *  "key" : rust_type = default_value => field_name;
+ * ```
+ *
+ * The supported types are `bool`, the integer types, the floating point types and `String`.
+ * See the module documentation for an example.
+ **/
+#[macro_export]
+macro_rules! servlet_config {
+    ($(config $name:ident { $($key:literal : $type:ty = $default:expr => $field:ident;)* })*) => {
+        $(
+        pub struct $name {
+            $(pub $field : $type,)*
+        }
+
+        impl $crate::config::ServletConfig for $name {
+            fn load(prefix:&str) -> $name
+            {
+                use $crate::config::{ConfigValueType, prefixed_key};
+
+                return $name {
+                    $($field : <$type as ConfigValueType>::read_config(&prefixed_key(prefix, $key), $default),)*
+                };
+            }
+        }
+        )*
+    }
+}
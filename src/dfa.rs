@@ -0,0 +1,263 @@
+// Copyright (C) 2018, Hao Hou
+
+//! The DFA driven pipe parser.
+//!
+//! For a persistent pipe, the data may arrive in several pieces, and each of them activates the
+//! servlet once. This module is the binding for the libpstd DFA runner, which feeds the pipe data
+//! to the parser char by char, and keeps the parser state attached to the pipe resource, so that
+//! the parsing resumes from the same state when the next piece of data arrives.
+//!
+//! Sample code:
+//! ```rust
+//!     struct LineParser;
+//!
+//!     impl DfaParser for LineParser {
+//!         type State = Vec<u8>;
+//!         fn process(&mut self, line:&mut Vec<u8>, ch:u8) -> Result<DfaAction, ()>
+//!         {
+//!             if ch == b'\n' { return Ok(DfaAction::Done); }
+//!             line.push(ch);
+//!             return Ok(DfaAction::Continue);
+//!         }
+//!         fn finish(&mut self, line:&mut Vec<u8>) -> Result<(), ()>
+//!         {
+//!             plumber_log!(N "Got line: {}", String::from_utf8_lossy(line));
+//!             return Ok(());
+//!         }
+//!     }
+//!
+//!     pipe_set! {
+//!         pipes ServletPipes {
+//!             "input" [PIPE_PERSIST] => input : DfaPipe;
+//!         }
+//!     }
+//!
+//!     // In the exec function
+//!     match LineParser.run(&self.pipes.input)? {
+//!         DfaState::Finished => { /* The whole line has been parsed */ },
+//!         _                  => { /* Wait for more data */ }
+//!     }
+//! ```
+
+use crate::pstd::{
+    pstd_dfa_ops_t,
+    pstd_dfa_process_param_t,
+    pstd_dfa_run,
+    pstd_dfa_done,
+    pstd_dfa_state_t_PSTD_DFA_FINISHED,
+    pstd_dfa_state_t_PSTD_DFA_EXHUASTED,
+    pstd_dfa_state_t_PSTD_DFA_WAITING
+};
+use crate::pipe::{Pipe, Input, PipeFlags, PipeKind, PipeDescriptor, PIPE_INPUT};
+
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr::null_mut;
+
+/**
+ * An input pipe port that is parsed by the DFA runner.
+ *
+ * The DFA runner attaches its own state to the pipe resource, so this type doesn't provide the
+ * pipe state API, and the pipe data should only be consumed by `DfaParser::run`. It can be
+ * declared in `pipe_set!` just like a `Pipe<Input>`.
+ **/
+pub struct DfaPipe {
+    /// The underlying input pipe
+    pipe : Pipe<Input>
+}
+
+impl PipeKind for DfaPipe {
+    const DIRECTION_FLAG : PipeFlags = PIPE_INPUT;
+}
+
+impl DfaPipe {
+    /**
+     * Define a new input pipe port that is parsed by the DFA runner, see `Pipe::define` for
+     * details.
+     *
+     * * `name` The name of the port
+     * * `flags` The initial pipe flag of this pipe
+     * * `type_expr` The type expression for the protocol of this pipe port
+     *
+     * Returns either `None` on creating failure or the newly created pipe object
+     **/
+    pub fn define(name:&str, flags:PipeFlags, type_expr:Option<&str>) -> Option<DfaPipe>
+    {
+        return Pipe::define(name, flags, type_expr).map(|pipe| DfaPipe { pipe : pipe });
+    }
+
+    /**
+     * Get the actual pipe descriptor managed by this pipe object
+     *
+     * Return the pipe descriptor
+     **/
+    pub fn as_descriptor(&self) -> PipeDescriptor
+    {
+        return self.pipe.as_descriptor();
+    }
+
+    /**
+     * Check if the pipe contains no more data, see `Pipe::eof` for details.
+     *
+     * Returns either None on error case or the check result
+     **/
+    pub fn eof(&mut self) -> Option<bool>
+    {
+        return self.pipe.eof();
+    }
+}
+
+/**
+ * The action the DFA should take after a char has been processed
+ **/
+pub enum DfaAction {
+    /// Continue feeding the next char to the parser
+    Continue,
+    /// The DFA has reached the finished state
+    Done
+}
+
+/**
+ * The state of the DFA after it has been run on the pipe
+ **/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfaState {
+    /// The DFA has reached the finished state
+    Finished,
+    /// All the pipe data has been consumed but the DFA hasn't finished yet
+    Exhausted,
+    /// The DFA is waiting for more data, the servlet shouldn't touch the pipe and should return
+    Waiting
+}
+
+/**
+ * The trait for a parser that is driven by the libpstd DFA runner.
+ *
+ * The parser is called from the libpstd callbacks, so a panic in the parser is caught and the
+ * DFA run fails instead.
+ **/
+pub trait DfaParser : Sized {
+    /**
+     * The type of the DFA state, which is kept across the servlet activations until the DFA
+     * finishes. The initial state is created with `Default`.
+     **/
+    type State : Default;
+
+    /**
+     * Process the next char of the pipe data
+     *
+     * * `state`: The current DFA state
+     * * `ch`: The next char
+     *
+     * Returns the action the DFA should take or an error
+     **/
+    fn process(&mut self, state:&mut Self::State, ch:u8) -> Result<DfaAction, ()>;
+
+    /**
+     * Called when the DFA has reached the finished state, before the state gets disposed
+     *
+     * * `state`: The final DFA state
+     *
+     * Returns the result of the post processing
+     **/
+    fn finish(&mut self, _state:&mut Self::State) -> Result<(), ()>
+    {
+        return Ok(());
+    }
+
+    /**
+     * Run the DFA on the pipe until the DFA finishes or all the available data is consumed. The
+     * DFA state is attached to the pipe resource until the DFA finishes.
+     *
+     * * `pipe`: The input pipe to parse
+     *
+     * Returns the state of the DFA or an error
+     **/
+    fn run(&mut self, pipe:&DfaPipe) -> Result<DfaState, ()>
+    {
+        return run_dfa(self, pipe);
+    }
+}
+
+// The parser code must not unwind across the libpstd callbacks, so a panic is reported as an error
+
+unsafe extern "C" fn create_state<P:DfaParser>() -> *mut c_void
+{
+    if let Ok(state) = catch_unwind(|| P::State::default())
+    {
+        return Box::into_raw(Box::new(state)) as *mut c_void;
+    }
+    return null_mut();
+}
+
+unsafe extern "C" fn dispose_state<P:DfaParser>(state:*mut c_void) -> c_int
+{
+    if !state.is_null()
+    {
+        if let Err(_) = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(state as *mut P::State))))
+        {
+            return -1;
+        }
+    }
+    return 0;
+}
+
+unsafe extern "C" fn process_char<P:DfaParser>(ch:c_char, param:pstd_dfa_process_param_t) -> c_int
+{
+    if let (Some(parser), Some(state)) = ((param.data as *mut P).as_mut(), (param.state as *mut P::State).as_mut())
+    {
+        match catch_unwind(AssertUnwindSafe(|| parser.process(state, ch as u8))) {
+            Ok(Ok(DfaAction::Continue)) => return 0,
+            Ok(Ok(DfaAction::Done))     => return pstd_dfa_done(param.dfa),
+            _                           => return -1
+        }
+    }
+    return -1;
+}
+
+unsafe extern "C" fn post_process<P:DfaParser>(param:pstd_dfa_process_param_t) -> c_int
+{
+    if let (Some(parser), Some(state)) = ((param.data as *mut P).as_mut(), (param.state as *mut P::State).as_mut())
+    {
+        if let Ok(Ok(_)) = catch_unwind(AssertUnwindSafe(|| parser.finish(state)))
+        {
+            return 0;
+        }
+    }
+    return -1;
+}
+
+/**
+ * Run the DFA parser on the pipe. See `DfaParser::run` for details.
+ *
+ * * `parser`: The parser
+ * * `pipe`: The input pipe to parse
+ *
+ * Returns the state of the DFA or an error
+ **/
+pub fn run_dfa<P:DfaParser>(parser:&mut P, pipe:&DfaPipe) -> Result<DfaState, ()>
+{
+    let ops = pstd_dfa_ops_t {
+        create_state  : Some(create_state::<P>),
+        dispose_state : Some(dispose_state::<P>),
+        process       : Some(process_char::<P>),
+        post_process  : Some(post_process::<P>)
+    };
+
+    let result = unsafe { pstd_dfa_run(pipe.as_descriptor(), ops, parser as *mut P as *mut c_void) };
+
+    if result == pstd_dfa_state_t_PSTD_DFA_FINISHED
+    {
+        return Ok(DfaState::Finished);
+    }
+    else if result == pstd_dfa_state_t_PSTD_DFA_EXHUASTED
+    {
+        return Ok(DfaState::Exhausted);
+    }
+    else if result == pstd_dfa_state_t_PSTD_DFA_WAITING
+    {
+        return Ok(DfaState::Waiting);
+    }
+
+    return Err(());
+}
@@ -0,0 +1,83 @@
+// Copyright (C) 2018, Hao Hou
+
+//! The process exit hooks.
+//!
+//! The servlet cleanup function isn't guaranteed to be called, for example, when the Plumber
+//! process is shutting down abnormally. This module allows the servlet to register the closures
+//! that are called when the Plumber process exits, which is the place to stop the background
+//! threads or flush the buffered data.
+//!
+//! ```rust
+//!     plumber_rs::on_exit(move || {
+//!         metrics.flush();
+//!     });
+//! ```
+
+use crate::pstd::pstd_onexit;
+use crate::log::log_write;
+
+use std::os::raw::c_void;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr::null_mut;
+use std::sync::Mutex;
+
+/**
+ * An exit hook
+ **/
+type ExitHook = Box<dyn FnOnce() + Send>;
+
+/**
+ * The registered exit hooks, None if the libpstd callback hasn't been registered yet
+ **/
+static EXIT_HOOKS : Mutex<Option<Vec<ExitHook>>> = Mutex::new(None);
+
+unsafe extern "C" fn run_exit_hooks(_data:*mut c_void)
+{
+    let hooks = match EXIT_HOOKS.lock() {
+        Ok(mut hooks)     => hooks.take(),
+        Err(poisoned)     => poisoned.into_inner().take()
+    };
+
+    if let Some(hooks) = hooks
+    {
+        for hook in hooks
+        {
+            if let Err(_) = catch_unwind(AssertUnwindSafe(hook))
+            {
+                log_write(1, file!(), line!() as i32, "The exit hook panicked");
+            }
+        }
+    }
+}
+
+/**
+ * Register a closure that is called when the Plumber process exits.
+ *
+ * The closures are called in the order they are registered. If a closure panics, the panic is
+ * logged and the remaining closures are still called.
+ *
+ * * `hook`: The closure to call
+ *
+ * Returns if the closure has been registered
+ **/
+pub fn on_exit<F>(hook:F) -> bool where F : FnOnce() + Send + 'static
+{
+    if let Ok(mut hooks) = EXIT_HOOKS.lock()
+    {
+        if hooks.is_none()
+        {
+            if unsafe { pstd_onexit(Some(run_exit_hooks), null_mut()) } < 0
+            {
+                return false;
+            }
+            *hooks = Some(Vec::new());
+        }
+
+        if let Some(ref mut hooks) = *hooks
+        {
+            hooks.push(Box::new(hook));
+            return true;
+        }
+    }
+    return false;
+}
@@ -0,0 +1,310 @@
+// Copyright (C) 2018, Hao Hou
+
+//! The binding for the Plumber shared file cache.
+//!
+//! The file cache is shared by all the servlets in the Plumber process, which is typically used
+//! by the servlets serving static content. A cached file can be read with the standard `Read` and
+//! `Seek` traits, or it can be committed to an output pipe as a byte-stream RLS object, so that the
+//! file body is read by the framework directly when it writes the data out, without any userspace
+//! copy:
+//!
+//! ```rust
+//!     if let Some(file) = CachedFile::open("/var/www/index.html")
+//!     {
+//!         let size = file.size();
+//!         // ... write the header
+//!         file.commit(&mut output).ok_or(())?;
+//!     }
+//! ```
+
+use crate::pstd::{
+    pstd_fcache_file_t,
+    pstd_fcache_open,
+    pstd_fcache_close,
+    pstd_fcache_read,
+    pstd_fcache_size,
+    pstd_fcache_eof,
+    pstd_fcache_is_in_cache,
+    pstd_fcache_stat,
+    pstd_fcache_seek,
+    pstd_scope_add,
+    scope_entity_t,
+    stat as c_stat
+};
+use crate::pipe::{Pipe, Output};
+
+use std::ffi::{CString, CStr};
+use std::io::{Read, Seek, SeekFrom, Result, Error, ErrorKind};
+use std::os::raw::{c_int, c_void};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::mem::zeroed;
+use std::ptr::null_mut;
+use std::convert::TryFrom;
+
+/**
+ * The metadata of a file, which is read through the file cache
+ **/
+#[derive(Debug, Clone, Copy)]
+pub struct FileStat {
+    /// The size of the file in bytes
+    pub size     : u64,
+    /// The file mode bits
+    pub mode     : u32,
+    /// The last modification time
+    pub modified : SystemTime
+}
+
+/**
+ * Check if the file is currently in the file cache
+ *
+ * * `path`: The path to the file
+ *
+ * Returns the check result
+ **/
+pub fn is_in_cache(path:&str) -> bool
+{
+    if let Ok(c_path) = CString::new(path)
+    {
+        return unsafe { pstd_fcache_is_in_cache(c_path.as_ptr()) } > 0;
+    }
+    return false;
+}
+
+/**
+ * Get the metadata of the file. If the metadata has been cached, no disk IO will be performed.
+ *
+ * * `path`: The path to the file
+ *
+ * Returns the file metadata or None on error
+ **/
+pub fn stat(path:&str) -> Option<FileStat>
+{
+    if let Ok(c_path) = CString::new(path)
+    {
+        let mut buf:c_stat = unsafe { zeroed() };
+
+        if unsafe { pstd_fcache_stat(c_path.as_ptr(), &mut buf as *mut c_stat) } >= 0
+        {
+            let modified = UNIX_EPOCH + Duration::new(buf.st_mtim.tv_sec as u64, buf.st_mtim.tv_nsec as u32);
+            return Some(FileStat {
+                size     : buf.st_size as u64,
+                mode     : buf.st_mode as u32,
+                modified : modified
+            });
+        }
+    }
+    return None;
+}
+
+/**
+ * A reference to a file in the file cache.
+ *
+ * The file is opened from the file cache, and if the file isn't cached yet, a new cache entry
+ * will be created. The reference is released when the object gets dropped.
+ **/
+pub struct CachedFile {
+    /// The file cache reference
+    file     : *mut pstd_fcache_file_t,
+    /// The path to the file
+    path     : CString,
+    /// The current offset in the file
+    position : u64
+}
+
+impl CachedFile {
+    /**
+     * Open a file from the file cache
+     *
+     * * `path`: The path to the file
+     *
+     * Returns the opened file or None on error
+     **/
+    pub fn open(path:&str) -> Option<CachedFile>
+    {
+        if let Ok(c_path) = CString::new(path)
+        {
+            let file = unsafe { pstd_fcache_open(c_path.as_ptr()) };
+
+            if !file.is_null()
+            {
+                return Some(CachedFile {
+                    file     : file,
+                    path     : c_path,
+                    position : 0
+                });
+            }
+        }
+        return None;
+    }
+
+    /**
+     * Get the path of the file
+     **/
+    pub fn path(&self) -> &str
+    {
+        let path:&CStr = &self.path;
+        return path.to_str().unwrap_or("");
+    }
+
+    /**
+     * Get the size of the file
+     *
+     * Returns the size or None on error
+     **/
+    pub fn size(&self) -> Option<u64>
+    {
+        let size = unsafe { pstd_fcache_size(self.file) };
+        if size == !0
+        {
+            return None;
+        }
+        return Some(size as u64);
+    }
+
+    /**
+     * Check if the file has reached the end
+     *
+     * Returns either None on error or the check result
+     **/
+    pub fn eof(&self) -> Option<bool>
+    {
+        let result = unsafe { pstd_fcache_eof(self.file) };
+        if result < 0
+        {
+            return None;
+        }
+        return Some(result > 0);
+    }
+
+    /**
+     * Commit the whole file to the output pipe as a byte-stream RLS object.
+     *
+     * The file content isn't copied to the pipe, instead the framework reads the file from the
+     * file cache when it writes the data out. The current offset of this reference doesn't affect
+     * the content being committed.
+     *
+     * * `pipe`: The output pipe
+     *
+     * Returns the operation result, `None` indicates failure
+     **/
+    pub fn commit<ST>(self, pipe:&mut Pipe<Output, ST>) -> Option<()>
+    {
+        let path = Box::new(self.path.clone());
+
+        let entity = scope_entity_t {
+            data       : Box::into_raw(path) as *mut c_void,
+            copy_func  : Some(rls_copy),
+            free_func  : Some(rls_free),
+            open_func  : Some(rls_open),
+            read_func  : Some(rls_read),
+            eos_func   : Some(rls_eos),
+            event_func : None,
+            close_func : Some(rls_close)
+        };
+
+        let token = unsafe { pstd_scope_add(&entity as *const scope_entity_t) };
+
+        if token as i32 == -1
+        {
+            unsafe { rls_free(entity.data) };
+            return None;
+        }
+
+        return pipe.write_scope_token(token);
+    }
+}
+
+impl Read for CachedFile {
+    fn read(&mut self, buf:&mut [u8]) -> Result<usize>
+    {
+        let result = unsafe { pstd_fcache_read(self.file, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+
+        if result == !0
+        {
+            return Err(Error::new(ErrorKind::Other, "Plumber pstd_fcache_read returns an error"));
+        }
+
+        self.position += result as u64;
+        return Ok(result);
+    }
+}
+
+impl Seek for CachedFile {
+    fn seek(&mut self, pos:SeekFrom) -> Result<u64>
+    {
+        let relative = |base:u64, offset:i64| {
+            return if offset >= 0 { base.checked_add(offset as u64) } else { base.checked_sub(offset.unsigned_abs()) };
+        };
+
+        let target = match pos {
+            SeekFrom::Start(offset)   => Some(offset),
+            SeekFrom::End(offset)     => match self.size() {
+                Some(size) => relative(size, offset),
+                None       => return Err(Error::new(ErrorKind::Other, "Plumber pstd_fcache_size returns an error"))
+            },
+            SeekFrom::Current(offset) => relative(self.position, offset)
+        };
+
+        let target = match target.and_then(|target| usize::try_from(target).ok()) {
+            Some(target) => target,
+            None         => return Err(Error::new(ErrorKind::InvalidInput, "Seeking to a negative or overflowing offset"))
+        };
+
+        if unsafe { pstd_fcache_seek(self.file, target) } < 0
+        {
+            return Err(Error::new(ErrorKind::Other, "Plumber pstd_fcache_seek returns an error"));
+        }
+
+        self.position = target as u64;
+        return Ok(self.position);
+    }
+}
+
+impl Drop for CachedFile {
+    fn drop(&mut self)
+    {
+        unsafe { pstd_fcache_close(self.file) };
+    }
+}
+
+unsafe extern "C" fn rls_copy(ptr:*const c_void) -> *mut c_void
+{
+    if let Some(path) = (ptr as *const CString).as_ref()
+    {
+        return Box::into_raw(Box::new(path.clone())) as *mut c_void;
+    }
+    return null_mut();
+}
+
+unsafe extern "C" fn rls_free(ptr:*mut c_void) -> c_int
+{
+    if !ptr.is_null()
+    {
+        drop(Box::from_raw(ptr as *mut CString));
+    }
+    return 0;
+}
+
+unsafe extern "C" fn rls_open(ptr:*const c_void) -> *mut c_void
+{
+    if let Some(path) = (ptr as *const CString).as_ref()
+    {
+        return pstd_fcache_open(path.as_ptr()) as *mut c_void;
+    }
+    return null_mut();
+}
+
+unsafe extern "C" fn rls_read(handle:*mut c_void, buffer:*mut c_void, bufsize:usize) -> usize
+{
+    return pstd_fcache_read(handle as *mut pstd_fcache_file_t, buffer, bufsize);
+}
+
+unsafe extern "C" fn rls_eos(handle:*const c_void) -> c_int
+{
+    return pstd_fcache_eof(handle as *const pstd_fcache_file_t);
+}
+
+unsafe extern "C" fn rls_close(handle:*mut c_void) -> c_int
+{
+    return pstd_fcache_close(handle as *mut pstd_fcache_file_t);
+}
@@ -39,6 +39,15 @@
 //! ```
 
 
+#[cfg(feature = "log-facade")]
+extern crate log as log_crate;
+
+#[cfg(feature = "tracing-layer")]
+extern crate tracing as tracing_crate;
+
+#[cfg(feature = "tracing-layer")]
+extern crate tracing_subscriber;
+
 #[macro_use]
 mod plumber_api_call;
 mod plumber_api;
@@ -50,6 +59,21 @@ pub mod rust_servlet;
 pub mod pipe;
 pub mod log;
 pub mod protocol;
+pub mod options;
+pub mod worker_local;
+pub mod mempool;
+pub mod fcache;
+pub mod dfa;
+pub mod config;
+pub mod exit;
+pub mod migrate;
+pub mod metadata;
+pub mod session;
+
+#[cfg(feature = "tracing-layer")]
+pub mod trace;
+
+pub use crate::exit::on_exit;
 
 /**
  * The type for the Plumber API address table
@@ -73,6 +97,10 @@ pub type VariadicWrapperFunc    = crate::va_list_helper::rust_va_list_wrapper_fu
  **/
 pub static mut API_ADDRESS_TABLE: Option<&'static ApiAddressTable> = None;
 
+#[cfg(feature = "mempool-global-alloc")]
+#[global_allocator]
+static GLOBAL_ALLOCATOR : mempool::PoolAllocator = mempool::PoolAllocator;
+
 #[allow(dead_code)]
 static mut VA_LIST_HELPER: VariadicWrapperFunc = None;
 
@@ -91,6 +119,7 @@ pub fn assign_address_table(api_table : *const ApiAddressTable, va_helpr: Variad
         API_ADDRESS_TABLE = api_table.as_ref();
         VA_LIST_HELPER    = va_helpr;
     }
+    mempool::mark_pool_ready();
 }
 
 /**
@@ -106,10 +135,21 @@ pub fn assign_address_table(api_table : *const ApiAddressTable, va_helpr: Variad
  *
  * To invoke this macro, you need a bootstrap class which carries all the information about the
  * Rust servlet. The bootstrap servlet must implemement trait `plumber_rs::servlet::Bootstrap`
+ *
+ * The logging integrations can be installed when the servlet is loaded, by listing them after the
+ * bootstrap type, for example `export_bootstrap!(Bootstrapper, log_facade, tracing)`:
+ *
+ * * `log_facade`: Forward the records from the `log` crate to the Plumber logging system. This
+ * requires the `log-facade` feature.
+ * * `tracing`: Install the Plumber tracing layer, see `plumber_rs::trace` for details. This
+ * requires the `tracing-layer` feature.
+ *
+ * The servlet metadata declared by the bootstrap type is exported as well, see
+ * `plumber_rs::metadata` for details.
  **/
 #[macro_export]
 macro_rules! export_bootstrap {
-    ($bs:ty) => {
+    (@emit $bs:ty, $on_load:block) => {
 
         #[allow(dead_code)]
         #[no_mangle]
@@ -120,6 +160,7 @@ macro_rules! export_bootstrap {
                                                va_helper : crate::plumber_rs::VariadicWrapperFunc) -> *mut crate::std::os::raw::c_void 
         {
             crate::plumber_rs::assign_address_table(address_table, va_helper);
+            $on_load
             return unsafe{ crate::plumber_rs::rust_servlet::call_bootstrap_obj::<$bs>(argc, argv, tm_ptr) };
         }
 
@@ -173,5 +214,183 @@ macro_rules! export_bootstrap {
         {
             crate::plumber_rs::rust_servlet::invoke_servlet_async_cleanup::<$bs>(obj_ptr, handle, task, type_inst)
         }
-    }
+    };
+    (@install log_facade) => {
+        crate::plumber_rs::log::install_log_facade();
+    };
+    (@install tracing) => {
+        crate::plumber_rs::trace::install_subscriber();
+    };
+    ($bs:ty $(, $integration:ident)*) => {
+        export_bootstrap!(@emit $bs, { $(export_bootstrap!(@install $integration);)* });
+        $crate::export_metadata!(None => $bs);
+    };
+}
+
+/**
+ * The macro that exports multiple servlets from one shared object. 
+ *
+ * Each servlet is identified by a name, and the servlet is selected by the first servlet argument
+ * after the servlet binary, for example, `language/rust libmy_servlets.so echo --verbose`. The
+ * servlet name is removed from the argument list before it's passed to the selected bootstrap
+ * type, so the servlet sees the same arguments as it's exported alone by `export_bootstrap`.
+ *
+ * ```rust
+ *     export_servlets! {
+ *         "echo"  => EchoBootstrap,
+ *         "hello" => HelloBootstrap
+ *     }
+ * ```
+ *
+ * The logging integrations can be installed after a semicolon, see `export_bootstrap` for details:
+ *
+ * ```rust
+ *     export_servlets! {
+ *         "echo"  => EchoBootstrap,
+ *         "hello" => HelloBootstrap;
+ *         log_facade
+ *     }
+ * ```
+ *
+ * The servlet metadata declared by each bootstrap type is exported along with the servlet name,
+ * see `plumber_rs::metadata` for details.
+ **/
+#[macro_export]
+macro_rules! export_servlets {
+    (@dispatch $index:expr, $($bs:ty),+ => |$bs_var:ident| $what:expr) => {{
+        let mut current = 0usize;
+        $(
+            if current == $index
+            {
+                type $bs_var = $bs;
+                return $what;
+            }
+            current += 1;
+        )+
+        let _ = current;
+    }};
+    ($($name:expr => $bs:ty),+ $(; $($integration:ident),*)?) => {
+
+        const _RS_SERVLET_NAMES : &'static [&'static str] = &[$($name),+];
+
+        $crate::export_metadata!($(Some($name) => $bs),+);
+
+        #[allow(dead_code)]
+        #[no_mangle]
+        pub extern "C" fn _rs_invoke_bootstrap(argc: u32, 
+                                               argv: *const *const crate::std::os::raw::c_char,
+                                               tm_ptr: *mut crate::std::os::raw::c_void,
+                                               address_table : *const crate::plumber_rs::ApiAddressTable, 
+                                               va_helper : crate::plumber_rs::VariadicWrapperFunc) -> *mut crate::std::os::raw::c_void 
+        {
+            use crate::plumber_rs::rust_servlet::{select_servlet, call_named_bootstrap_obj, wrap_multi_object};
+
+            crate::plumber_rs::assign_address_table(address_table, va_helper);
+            $($(export_bootstrap!(@install $integration);)*)?
+
+            if let Some((index, args)) = unsafe { select_servlet(argc, argv, _RS_SERVLET_NAMES) }
+            {
+                export_servlets!(@dispatch index, $($bs),+ => |__PlumberRsBootstrap| {
+                    wrap_multi_object(index, unsafe { call_named_bootstrap_obj::<__PlumberRsBootstrap>(_RS_SERVLET_NAMES[index], args.len() as u32, args.as_ptr(), tm_ptr) })
+                });
+            }
+
+            return crate::std::ptr::null_mut();
+        }
+
+        #[allow(dead_code)]
+        #[no_mangle]
+        pub extern "C" fn _rs_invoke_init(obj_ptr    : *mut crate::std::os::raw::c_void, 
+                                          argc       : u32, 
+                                          argv       : *const *const crate::std::os::raw::c_char) -> i32 
+        {
+            use crate::plumber_rs::rust_servlet::{select_servlet, unwrap_multi_object, invoke_servlet_init};
+
+            if let (Some((index, obj)), Some((_, args))) = unsafe { (unwrap_multi_object(obj_ptr), select_servlet(argc, argv, _RS_SERVLET_NAMES)) }
+            {
+                export_servlets!(@dispatch index, $($bs),+ => |__PlumberRsBootstrap| invoke_servlet_init::<__PlumberRsBootstrap>(obj, args.len() as u32, args.as_ptr()));
+            }
+
+            return -1;
+        }
+
+        #[allow(dead_code)]
+        #[no_mangle]
+        pub extern "C" fn _rs_invoke_exec(obj_ptr   : *mut crate::std::os::raw::c_void, 
+                                          type_inst : *mut crate::std::os::raw::c_void) -> i32 
+        {
+            use crate::plumber_rs::rust_servlet::{unwrap_multi_object, invoke_servlet_sync_exec};
+
+            if let Some((index, obj)) = unsafe { unwrap_multi_object(obj_ptr) }
+            {
+                export_servlets!(@dispatch index, $($bs),+ => |__PlumberRsBootstrap| invoke_servlet_sync_exec::<__PlumberRsBootstrap>(obj, type_inst));
+            }
+
+            return -1;
+        }
+
+        #[allow(dead_code)]
+        #[no_mangle]
+        pub extern "C" fn _rs_invoke_cleanup(obj_ptr : *mut crate::std::os::raw::c_void) -> i32 
+        {
+            use crate::plumber_rs::rust_servlet::{unwrap_multi_object, dispose_multi_object, invoke_servlet_cleanup};
+
+            if let Some((index, obj)) = unsafe { unwrap_multi_object(obj_ptr) }
+            {
+                unsafe { dispose_multi_object(obj_ptr) };
+                export_servlets!(@dispatch index, $($bs),+ => |__PlumberRsBootstrap| invoke_servlet_cleanup::<__PlumberRsBootstrap>(obj));
+            }
+
+            return -1;
+        }
+
+        #[allow(dead_code)]
+        #[no_mangle]
+        pub extern "C" fn _rs_invoke_async_init(obj_ptr  : *mut crate::std::os::raw::c_void, 
+                                                handle   : *mut crate::std::os::raw::c_void,
+                                                type_inst: *mut crate::std::os::raw::c_void) -> *mut crate::std::os::raw::c_void
+        {
+            use crate::plumber_rs::rust_servlet::{unwrap_multi_object, wrap_multi_object, invoke_servlet_async_init};
+
+            if let Some((index, obj)) = unsafe { unwrap_multi_object(obj_ptr) }
+            {
+                export_servlets!(@dispatch index, $($bs),+ => |__PlumberRsBootstrap| wrap_multi_object(index, invoke_servlet_async_init::<__PlumberRsBootstrap>(obj, handle, type_inst)));
+            }
+
+            return crate::std::ptr::null_mut();
+        }
+
+        #[allow(dead_code)]
+        #[no_mangle]
+        pub extern "C" fn _rs_invoke_async_exec(handle : *mut crate::std::os::raw::c_void, 
+                                                task : *mut crate::std::os::raw::c_void) -> i32
+        {
+            use crate::plumber_rs::rust_servlet::{unwrap_multi_object, invoke_servlet_async_exec};
+
+            if let Some((index, task_data)) = unsafe { unwrap_multi_object(task) }
+            {
+                export_servlets!(@dispatch index, $($bs),+ => |__PlumberRsBootstrap| invoke_servlet_async_exec::<__PlumberRsBootstrap>(handle, task_data));
+            }
+
+            return -1;
+        }
+
+        #[allow(dead_code)]
+        #[no_mangle]
+        pub extern "C" fn _rs_invoke_async_cleanup(obj_ptr   : *mut crate::std::os::raw::c_void, 
+                                                   handle    : *mut crate::std::os::raw::c_void, 
+                                                   task      : *mut crate::std::os::raw::c_void,
+                                                   type_inst : *mut crate::std::os::raw::c_void) -> i32
+        {
+            use crate::plumber_rs::rust_servlet::{unwrap_multi_object, dispose_multi_object, invoke_servlet_async_cleanup};
+
+            if let (Some((index, obj)), Some((_, task_data))) = unsafe { (unwrap_multi_object(obj_ptr), unwrap_multi_object(task)) }
+            {
+                unsafe { dispose_multi_object(task) };
+                export_servlets!(@dispatch index, $($bs),+ => |__PlumberRsBootstrap| invoke_servlet_async_cleanup::<__PlumberRsBootstrap>(obj, handle, task_data, type_inst));
+            }
+
+            return -1;
+        }
+    };
 }
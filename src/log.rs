@@ -3,7 +3,8 @@
 //! The binding for the Plumber framework logging system.
 //!
 //! With the plumber_log macro, the Rust servlet is able to emit log to the Plumber logging system
-//! directly.
+//! directly. The log carries the path of the function that emits it, and the message is only
+//! formatted when its level passes the log level threshold, see `log_level` for details.
 //!
 //! Sample code:
 //! ```rust
@@ -13,22 +14,182 @@
 //!     //...
 //! ```
 
-use crate::plumber_api_call::get_cstr;
 use crate::va_list_helper::{__va_list_tag};
 use crate::VA_LIST_HELPER;
+use crate::config::get_numeric;
 
-use std::os::raw::c_void;
+use std::os::raw::{c_void, c_char};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::fmt::{Arguments, Display, Formatter, Write};
+
+/**
+ * The size of the stack buffer used to format the log message. Longer messages fall back to a
+ * heap allocated buffer.
+ **/
+const LOG_BUFFER_SIZE : usize = 1024;
+
+/**
+ * The size of the stack buffer used for the file name and function name
+ **/
+const LOG_NAME_BUFFER_SIZE : usize = 256;
+
+/**
+ * The library configuration key which sets the log level threshold of the Rust servlets
+ **/
+const LOG_LEVEL_CONF_KEY : &'static str = "plumber_rs.log_level";
+
+/**
+ * The log level threshold, a negative value means it hasn't been queried yet
+ **/
+static LOG_LEVEL_THRESHOLD : AtomicI32 = AtomicI32::new(-1);
+
+/**
+ * If the log level threshold has been set by `set_log_level`, which stops the configuration from
+ * being read again
+ **/
+static LOG_LEVEL_OVERRIDDEN : AtomicBool = AtomicBool::new(false);
 
 /**
  * The helper data for the log function
  **/
-struct LogWriteData<'a> {
+struct LogWriteData {
     level:i32,
-    file:&'a str,
-    func:&'a str,
+    file:*const c_char,
+    func:*const c_char,
     line:i32
 }
 
+/**
+ * A NUL terminated text buffer which formats into a stack array, and only falls back to the heap
+ * when the text doesn't fit
+ **/
+struct LogBuffer<const N:usize> {
+    stack : [u8; N],
+    len   : usize,
+    heap  : Option<Vec<u8>>
+}
+
+impl <const N:usize> LogBuffer<N> {
+    fn new() -> LogBuffer<N>
+    {
+        return LogBuffer {
+            stack : [0; N],
+            len   : 0,
+            heap  : None
+        };
+    }
+
+    fn push(&mut self, bytes:&[u8])
+    {
+        if let Some(ref mut heap) = self.heap
+        {
+            heap.extend_from_slice(bytes);
+            return;
+        }
+
+        if self.len + bytes.len() < N
+        {
+            self.stack[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+        else
+        {
+            let mut heap = Vec::with_capacity(self.len + bytes.len() + 1);
+            heap.extend_from_slice(&self.stack[..self.len]);
+            heap.extend_from_slice(bytes);
+            self.heap = Some(heap);
+        }
+    }
+
+    /**
+     * Terminate the text with a NUL byte and get the C string pointer to it
+     **/
+    fn terminate(&mut self) -> *const c_char
+    {
+        if let Some(ref mut heap) = self.heap
+        {
+            heap.push(0);
+            return heap.as_ptr() as *const c_char;
+        }
+
+        self.stack[self.len] = 0;
+        return self.stack.as_ptr() as *const c_char;
+    }
+}
+
+impl <const N:usize> Write for LogBuffer<N> {
+    fn write_str(&mut self, s:&str) -> std::fmt::Result
+    {
+        self.push(s.as_bytes());
+        return Ok(());
+    }
+}
+
+/**
+ * Get the current log level threshold. The messages with a level number larger than the threshold
+ * are dropped before they get formatted.
+ *
+ * The threshold is read from the library configuration `plumber_rs.log_level` when the first log
+ * is written, which can be set with the PSS code `plumber.std.libconf.plumber_rs.log_level = 4`.
+ * The configuration is read again each time a servlet is bootstrapped, so a new value takes effect
+ * from the next servlet that is created. By default all the messages are passed to the Plumber
+ * logging system.
+ *
+ * Returns the log level threshold
+ **/
+pub fn log_level() -> i32
+{
+    let level = LOG_LEVEL_THRESHOLD.load(Ordering::Relaxed);
+
+    if level >= 0
+    {
+        return level;
+    }
+
+    let level = get_numeric(LOG_LEVEL_CONF_KEY, 6) as i32;
+    let level = if level < 0 { 0 } else { level };
+
+    LOG_LEVEL_THRESHOLD.store(level, Ordering::Relaxed);
+
+    return level;
+}
+
+/**
+ * Read the log level threshold from the library configuration again, unless it has been
+ * overridden by `set_log_level`. This is called each time a servlet is bootstrapped.
+ **/
+pub fn reload_log_level()
+{
+    if !LOG_LEVEL_OVERRIDDEN.load(Ordering::Relaxed)
+    {
+        LOG_LEVEL_THRESHOLD.store(-1, Ordering::Relaxed);
+    }
+}
+
+/**
+ * Override the log level threshold for this servlet library. Once it's called, the library
+ * configuration `plumber_rs.log_level` is ignored.
+ *
+ * * `level`: The new log level threshold, 0 only allows fatal messages and 6 allows all messages
+ **/
+pub fn set_log_level(level:i32)
+{
+    LOG_LEVEL_OVERRIDDEN.store(true, Ordering::Relaxed);
+    LOG_LEVEL_THRESHOLD.store(if level < 0 { 0 } else { level }, Ordering::Relaxed);
+}
+
+/**
+ * Check if the message with the given level passes the log level threshold
+ *
+ * * `level`: The log level number
+ *
+ * Returns the check result
+ **/
+pub fn log_enabled(level:i32) -> bool
+{
+    return level <= log_level();
+}
+
 /**
  * Write a log to the Plumber logging system.
  *
@@ -42,6 +203,46 @@ struct LogWriteData<'a> {
  **/
 pub fn log_write(level:i32, file:&str, line:i32, message:&str) 
 {
+    log_write_func(level, file, "????", line, message);
+}
+
+/**
+ * Write a log to the Plumber logging system with the name of the function or module that emits
+ * the log.
+ *
+ * * `level` The log level number. 0 is the highest level (fatal) and 6 is the lowest level
+ * (debug).
+ * * `file` The file name of the source code that calls this logging function
+ * * `func` The name of the function or module that calls this logging function
+ * * `line` The line number of the call site
+ * * `message` The log message needs to be send to Plumber logging system
+ **/
+pub fn log_write_func(level:i32, file:&str, func:&str, line:i32, message:&str)
+{
+    log_write_fmt(level, file, func, line, format_args!("{}", message));
+}
+
+/**
+ * Format and write a log to the Plumber logging system. 
+ *
+ * The message is only formatted when the level passes the log level threshold, and it's formatted
+ * into a stack buffer unless it's too long.
+ *
+ * * `level` The log level number. 0 is the highest level (fatal) and 6 is the lowest level
+ * (debug).
+ * * `file` The file name of the source code that calls this logging function
+ * * `func` The name of the function or module that calls this logging function
+ * * `line` The line number of the call site
+ * * `args` The format arguments of the message
+ *
+ * *This function should be rarely used manually, the normal way to use it is macro `plumber_log!`*
+ **/
+pub fn log_write_fmt(level:i32, file:&str, func:&str, line:i32, args:Arguments)
+{
+    if !log_enabled(level)
+    {
+        return;
+    }
 
     if let Some(ref va_helper) = unsafe{VA_LIST_HELPER} 
     {
@@ -49,35 +250,232 @@ pub fn log_write(level:i32, file:&str, line:i32, message:&str)
         {
             if let Some(log_data) = unsafe{ (data as *mut LogWriteData).as_ref() }
             {
-
-                let (c_format,  _format) = get_cstr(Some("%s"));
-                let (c_file, _file) = get_cstr(Some(log_data.file));
-                let (c_func, _func) = get_cstr(Some(log_data.func));
+                let c_format = b"%s\0".as_ptr() as *const c_char;
 
                 plumber_api_call! {
-                    log_write(log_data.level, c_file, c_func, log_data.line, c_format, ap as *mut crate::plumber_api::__va_list_tag) in {}
+                    log_write(log_data.level, log_data.file, log_data.func, log_data.line, c_format, ap as *mut crate::plumber_api::__va_list_tag) in {}
                 }
             }
                 
         }
 
-        let mut data = LogWriteData{ level: level, file: file, func: "????", line: line};
-        let mut data_ptr = &mut data as *mut LogWriteData;
+        let mut file_buf = LogBuffer::<LOG_NAME_BUFFER_SIZE>::new();
+        let mut func_buf = LogBuffer::<LOG_NAME_BUFFER_SIZE>::new();
+        let mut message_buf = LogBuffer::<LOG_BUFFER_SIZE>::new();
+
+        file_buf.push(file.as_bytes());
+        func_buf.push(func.as_bytes());
+        let _ = message_buf.write_fmt(args);
 
-        let (c_message, _message) = get_cstr(Some(message));
+        let mut data = LogWriteData{ level: level, file: file_buf.terminate(), func: func_buf.terminate(), line: line};
+        let data_ptr = &mut data as *mut LogWriteData;
 
-        unsafe{va_helper(Some(log_write_cont), data_ptr as *mut c_void , c_message)};
+        unsafe{va_helper(Some(log_write_cont), data_ptr as *mut c_void , message_buf.terminate())};
     }
 }
 
+/**
+ * The key-value fields attached to a structured log message.
+ *
+ * The fields are rendered in the logfmt form, for example, `user_id=42 path="/index.html"`.
+ * The value is quoted when it's empty or contains spaces, quotes, `=` or control chars, and the
+ * quotes and backslashes in a quoted value are escaped.
+ *
+ * *This is normally created by the macro `plumber_log!`, see its documentation for details*
+ **/
+pub struct LogFields<'a>(pub &'a [(&'a str, LogFieldValue<'a>)]);
+
+/**
+ * The value of a structured log field
+ **/
+pub type LogFieldValue<'a> = &'a dyn Display;
+
+/**
+ * The writer that checks if the value needs to be quoted, without actually storing the value
+ **/
+struct QuoteChecker {
+    empty      : bool,
+    need_quote : bool
+}
+
+impl Write for QuoteChecker {
+    fn write_str(&mut self, s:&str) -> std::fmt::Result
+    {
+        self.empty = self.empty && s.is_empty();
+        self.need_quote = self.need_quote || s.chars().any(|ch| ch == ' ' || ch == '"' || ch == '=' || ch == '\\' || ch.is_control());
+        return Ok(());
+    }
+}
+
+/**
+ * The writer that escapes the quotes, backslashes and control chars in a quoted value
+ **/
+struct QuoteEscaper<'a, 'b: 'a>(&'a mut Formatter<'b>);
+
+impl <'a, 'b> Write for QuoteEscaper<'a, 'b> {
+    fn write_str(&mut self, s:&str) -> std::fmt::Result
+    {
+        for ch in s.chars()
+        {
+            match ch {
+                '"'  => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                '\t' => self.0.write_str("\\t")?,
+                _    => self.0.write_char(ch)?
+            }
+        }
+        return Ok(());
+    }
+}
+
+impl <'a> Display for LogFields<'a> {
+    fn fmt(&self, f:&mut Formatter) -> std::fmt::Result
+    {
+        for &(key, value) in self.0.iter()
+        {
+            let mut checker = QuoteChecker { empty: true, need_quote: false };
+            write!(checker, "{}", value)?;
+
+            if checker.empty || checker.need_quote
+            {
+                write!(f, " {}=\"", key)?;
+                write!(QuoteEscaper(f), "{}", value)?;
+                f.write_char('"')?;
+            }
+            else
+            {
+                write!(f, " {}={}", key, value)?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+/**
+ * Get the type name of the value, this is used to get the name of the function that emits the log
+ **/
+#[doc(hidden)]
+pub fn type_name_of<T>(_value:T) -> &'static str
+{
+    return std::any::type_name::<T>();
+}
+
+/**
+ * Get the enclosing function path from the type name of the marker function defined by the macro
+ * `plumber_func_name!`
+ **/
+#[doc(hidden)]
+pub fn strip_func_name(name:&'static str) -> &'static str
+{
+    let mut name = name.trim_end_matches("::__plumber_func_marker");
+    while name.ends_with("::{{closure}}")
+    {
+        name = &name[..name.len() - "::{{closure}}".len()];
+    }
+    return name;
+}
+
+/**
+ * The `log` crate facade which forwards all the log records to the Plumber logging system.
+ *
+ * The `log` crate has fewer levels than Plumber, so the levels are mapped as following:
+ * `Error` to error, `Warn` to warning, `Info` to info, `Debug` to trace and `Trace` to debug.
+ *
+ * This is only available with the `log-facade` feature, and the normal way to install it is
+ * `export_bootstrap!(Bootstrapper, log_facade);`
+ **/
+#[cfg(feature = "log-facade")]
+pub struct PlumberLogger;
+
+#[cfg(feature = "log-facade")]
+static PLUMBER_LOGGER : PlumberLogger = PlumberLogger;
+
+#[cfg(feature = "log-facade")]
+fn map_log_crate_level(level:log_crate::Level) -> i32
+{
+    return match level {
+        log_crate::Level::Error => 1,
+        log_crate::Level::Warn  => 2,
+        log_crate::Level::Info  => 4,
+        log_crate::Level::Debug => 5,
+        log_crate::Level::Trace => 6
+    };
+}
+
+#[cfg(feature = "log-facade")]
+impl log_crate::Log for PlumberLogger {
+    fn enabled(&self, metadata:&log_crate::Metadata) -> bool
+    {
+        return log_enabled(map_log_crate_level(metadata.level()));
+    }
+
+    fn log(&self, record:&log_crate::Record)
+    {
+        let level = map_log_crate_level(record.level());
+
+        let file = record.file().unwrap_or("<unknown>");
+        let func = record.module_path().unwrap_or(record.target());
+        let line = record.line().unwrap_or(0) as i32;
+
+        log_write_fmt(level, file, func, line, *record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+/**
+ * Install the `log` crate facade, after that all the records emitted with the `log` crate will
+ * be forwarded to the Plumber logging system.
+ *
+ * *This function is called by `export_bootstrap!(Bootstrapper, log_facade)`, and it's rarely
+ * needed to call it manually.*
+ *
+ * Returns if the facade has been installed, it fails when another logger has been installed
+ **/
+#[cfg(feature = "log-facade")]
+pub fn install_log_facade() -> bool
+{
+    if let Ok(_) = log_crate::set_logger(&PLUMBER_LOGGER)
+    {
+        log_crate::set_max_level(log_crate::LevelFilter::Trace);
+        return true;
+    }
+    return false;
+}
+
+/**
+ * Get the path of the enclosing function, for example, `my_servlet::Servlet::exec`
+ **/
+#[macro_export]
+macro_rules! plumber_func_name {
+    () => {{
+        fn __plumber_func_marker() {}
+        $crate::log::strip_func_name($crate::log::type_name_of(__plumber_func_marker))
+    }}
+}
+
 /**
  * Write the log with specified level to Plumber logging system
  **/
 #[macro_export]
 macro_rules! plumber_log_write {
+    ($level:expr, $fmt:expr $(, $arg:expr)* ; $($key:ident = $value:expr),+ $(,)*) => {{
+        let level = $level;
+        if $crate::log::log_enabled(level)
+        {
+            $crate::log::log_write_fmt(level, file!(), $crate::plumber_func_name!(), line!() as i32, 
+                                       format_args!("{}{}", format_args!($fmt $(, $arg)*), 
+                                                    $crate::log::LogFields(&[$((stringify!($key), &$value as $crate::log::LogFieldValue)),+])));
+        }
+    }};
     ($level:expr,  $($arg:tt)*) => {{
-        use plumber_rs::log::log_write;
-        log_write($level, file!(), line!() as i32, &(format!($($arg)*))[0..]);
+        let level = $level;
+        if $crate::log::log_enabled(level)
+        {
+            $crate::log::log_write_fmt(level, file!(), $crate::plumber_func_name!(), line!() as i32, format_args!($($arg)*));
+        }
     }}
 }
 
@@ -103,14 +501,55 @@ macro_rules! plumber_log_write {
  *     plumber_log(D "Debug message {}", "hello");
  *     //...
  * ```
+ *
+ * The key-value fields can be attached to the message after a semicolon, and they are rendered
+ * in the logfmt form, so that the log pipeline can index them:
+ *
+ * ```rust
+ *     plumber_log!(W "request failed"; user_id = id, latency_ms = t);
+ *     // Emits: request failed user_id=42 latency_ms=120
+ * ```
  * **/
 #[macro_export]
 macro_rules! plumber_log {
-    (F $($arg:tt)*) => { plumber_log_write!(0, $($arg)*); };
-    (E $($arg:tt)*) => { plumber_log_write!(1, $($arg)*); };
-    (W $($arg:tt)*) => { plumber_log_write!(2, $($arg)*); };
-    (N $($arg:tt)*) => { plumber_log_write!(3, $($arg)*); };
-    (I $($arg:tt)*) => { plumber_log_write!(4, $($arg)*); };
-    (T $($arg:tt)*) => { plumber_log_write!(5, $($arg)*); };
-    (D $($arg:tt)*) => { plumber_log_write!(6, $($arg)*); };
+    (F $($arg:tt)*) => { $crate::plumber_log_write!(0, $($arg)*); };
+    (E $($arg:tt)*) => { $crate::plumber_log_write!(1, $($arg)*); };
+    (W $($arg:tt)*) => { $crate::plumber_log_write!(2, $($arg)*); };
+    (N $($arg:tt)*) => { $crate::plumber_log_write!(3, $($arg)*); };
+    (I $($arg:tt)*) => { $crate::plumber_log_write!(4, $($arg)*); };
+    (T $($arg:tt)*) => { $crate::plumber_log_write!(5, $($arg)*); };
+    (D $($arg:tt)*) => { $crate::plumber_log_write!(6, $($arg)*); };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_values_are_not_quoted()
+    {
+        let text = format!("{}", LogFields(&[("user_id", &42 as LogFieldValue), ("path", &"/index.html")]));
+        assert_eq!(text, " user_id=42 path=/index.html");
+    }
+
+    #[test]
+    fn special_values_are_quoted()
+    {
+        let text = format!("{}", LogFields(&[("empty", &"" as LogFieldValue), ("name", &"hello world"), ("expr", &"a=b")]));
+        assert_eq!(text, " empty=\"\" name=\"hello world\" expr=\"a=b\"");
+    }
+
+    #[test]
+    fn quoted_values_are_escaped()
+    {
+        let text = format!("{}", LogFields(&[("msg", &"say \"hi\"\n\tC:\\tmp\r" as LogFieldValue)]));
+        assert_eq!(text, " msg=\"say \\\"hi\\\"\\n\\tC:\\\\tmp\\r\"");
+    }
+
+    #[test]
+    fn closures_are_stripped_from_func_name()
+    {
+        assert_eq!(strip_func_name("servlet::Servlet::exec::__plumber_func_marker"), "servlet::Servlet::exec");
+        assert_eq!(strip_func_name("servlet::exec::{{closure}}::{{closure}}::__plumber_func_marker"), "servlet::exec");
+    }
 }
@@ -0,0 +1,495 @@
+// Copyright (C) 2018, Hao Hou
+
+//! The binding for the Plumber memory pool.
+//!
+//! Plumber has a pooled allocator which is optimized for the small allocations that are made and
+//! freed during a single request. This module makes it available to the Rust servlet in three
+//! ways:
+//!
+//! * `PoolBox<T>`: A box whose memory comes from the memory pool
+//! * `PoolPage`: An entire page allocated from the memory pool
+//! * `PoolAllocator`: A `GlobalAlloc` implementation, which can be installed by the servlet with
+//! `#[global_allocator]`, or by enabling the `mempool-global-alloc` feature of this crate.
+//!
+//! The memory pool doesn't document the alignment of the memory it returns, so this module
+//! expects `MEMPOOL_ALIGN` and checks every pointer the memory pool returns. A misaligned pointer
+//! is returned to the memory pool, and the allocation either fails, for `PoolBox::new`, or is
+//! served by the system allocator, for `PoolAllocator`. The allocations with a larger alignment
+//! requirement are never served by the memory pool.
+//!
+//! The memory pool is only usable after the servlet is loaded and before libpstd is finalized,
+//! but the global allocator is also called outside of this period, for example, by the Rust
+//! runtime before the servlet is loaded. So `PoolAllocator` uses the system allocator when the
+//! memory pool isn't ready, and it tags each allocation, so the memory is always returned to the
+//! allocator it comes from. The pool memory freed after libpstd is finalized is leaked, which
+//! applies to `PoolBox` and `PoolPage` as well.
+//!
+//! libpstd doesn't document if the memory can be returned to the memory pool by a thread other
+//! than the one allocated it, so the pool memory is always freed by the thread that allocated it.
+//! `PoolBox` and `PoolPage` can't be sent to another thread. `PoolAllocator` records the thread
+//! of each pool allocation, and the memory freed by other threads is queued, and returned to the
+//! memory pool when the allocating thread calls the allocator again. The queued memory of a thread
+//! that has exited is leaked. Only the first `MAX_POOL_THREADS` threads that use `PoolAllocator`
+//! get the pool memory, the other threads are served by the system allocator.
+
+use crate::pstd::{
+    pstd_mempool_alloc,
+    pstd_mempool_free,
+    pstd_mempool_page_alloc,
+    pstd_mempool_page_dealloc,
+    pstd_onexit,
+    getpagesize
+};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::ops::{Deref, DerefMut};
+use std::os::raw::c_void;
+use std::ptr::{NonNull, drop_in_place, write_bytes, null_mut};
+use std::mem::{align_of, size_of, forget};
+use std::slice::{from_raw_parts, from_raw_parts_mut};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/**
+ * The alignment that the memory pool is expected to guarantee, which is checked for each
+ * allocation
+ **/
+pub const MEMPOOL_ALIGN : usize = 8;
+
+/// The memory pool hasn't been initialized
+const POOL_NOT_READY : usize = 0;
+/// The memory pool is usable
+const POOL_READY     : usize = 1;
+/// The libpstd has been finalized
+const POOL_FINALIZED : usize = 2;
+
+/**
+ * The state of the memory pool
+ **/
+static POOL_STATE : AtomicUsize = AtomicUsize::new(POOL_NOT_READY);
+
+unsafe extern "C" fn finalize_pool(_data:*mut c_void)
+{
+    POOL_STATE.store(POOL_FINALIZED, Ordering::SeqCst);
+}
+
+/**
+ * Mark the memory pool as usable.
+ *
+ * This function is desgined to be called from the `export_bootstrap` marco only, after the
+ * servlet has been loaded. It doesn't allocate any memory, because it can be called while the
+ * global allocator is the `PoolAllocator`.
+ **/
+pub fn mark_pool_ready()
+{
+    if POOL_STATE.compare_exchange(POOL_NOT_READY, POOL_READY, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    {
+        if unsafe { pstd_onexit(Some(finalize_pool), null_mut()) } < 0
+        {
+            POOL_STATE.store(POOL_NOT_READY, Ordering::SeqCst);
+        }
+    }
+}
+
+/**
+ * Check if the memory pool is usable
+ **/
+pub fn pool_ready() -> bool
+{
+    return POOL_STATE.load(Ordering::SeqCst) == POOL_READY;
+}
+
+/**
+ * Check if libpstd has been finalized, the memory can't be returned to the memory pool after that
+ **/
+fn pool_finalized() -> bool
+{
+    return POOL_STATE.load(Ordering::SeqCst) == POOL_FINALIZED;
+}
+
+/**
+ * Check if the memory pool can serve an allocation with the given size and alignment
+ *
+ * * `size`: The number of bytes requested from the memory pool
+ * * `align`: The required alignment
+ *
+ * Returns the check result
+ **/
+fn pool_servable(size:usize, align:usize) -> bool
+{
+    return align <= MEMPOOL_ALIGN && size <= u32::max_value() as usize;
+}
+
+/**
+ * Check if the pointer returned by the memory pool has the expected alignment
+ **/
+fn pool_aligned(ptr:*mut c_void) -> bool
+{
+    return (ptr as usize) % MEMPOOL_ALIGN == 0;
+}
+
+/**
+ * The size of the tag before each allocation served by `PoolAllocator`, which is also the offset
+ * between the allocated memory and the returned pointer. The first word is the tag, and the second
+ * word is the thread that allocated the pool memory.
+ **/
+const TAG_SIZE   : usize = 2 * MEMPOOL_ALIGN;
+/// The tag of the memory that comes from the memory pool
+const POOL_TAG   : usize = 0x504f4f4c;
+/// The tag of the memory that comes from the system allocator
+const SYSTEM_TAG : usize = 0x53595354;
+
+/**
+ * Check if the allocation should be tagged, which means it may be served by the memory pool
+ **/
+fn tagged(layout:&Layout) -> bool
+{
+    return pool_servable(layout.size() + TAG_SIZE, layout.align());
+}
+
+/**
+ * The most threads that can allocate the pool memory through `PoolAllocator`
+ **/
+pub const MAX_POOL_THREADS : usize = 256;
+
+/// The id of the next thread that uses `PoolAllocator`, 0 is never used
+static NEXT_THREAD_ID : AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    /// The id of current thread, 0 means it hasn't been assigned
+    static THREAD_ID : Cell<usize> = const { Cell::new(0) };
+}
+
+/**
+ * The pool memory freed by the threads other than the one allocated it, one queue for each thread.
+ * The queued memory is linked by the first word of the tag.
+ **/
+static DEFERRED_FREE : [AtomicPtr<u8>; MAX_POOL_THREADS] = [const { AtomicPtr::new(null_mut()) }; MAX_POOL_THREADS];
+
+/**
+ * Get the id of current thread, which is also the index of its queue in `DEFERRED_FREE`.
+ *
+ * Returns the thread id, or None if current thread can't use the pool memory
+ **/
+fn pool_thread() -> Option<usize>
+{
+    let id = THREAD_ID.try_with(|id| {
+        if id.get() == 0
+        {
+            id.set(NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed));
+        }
+        return id.get();
+    }).ok()?;
+
+    if id < MAX_POOL_THREADS
+    {
+        return Some(id);
+    }
+    return None;
+}
+
+/**
+ * Queue the pool memory freed by a thread other than the one allocated it
+ *
+ * * `owner`: The thread that allocated the memory
+ * * `base`: The memory to free
+ **/
+unsafe fn defer_free(owner:usize, base:*mut u8)
+{
+    let queue = &DEFERRED_FREE[owner];
+    let mut head = queue.load(Ordering::Relaxed);
+
+    loop
+    {
+        (base as *mut *mut u8).write(head);
+
+        match queue.compare_exchange_weak(head, base, Ordering::Release, Ordering::Relaxed)
+        {
+            Ok(_) => return,
+            Err(current) => head = current
+        }
+    }
+}
+
+/**
+ * Return the memory queued for current thread to the memory pool
+ *
+ * * `owner`: The id of current thread
+ **/
+unsafe fn free_deferred(owner:usize)
+{
+    let queue = &DEFERRED_FREE[owner];
+
+    if queue.load(Ordering::Relaxed).is_null()
+    {
+        return;
+    }
+
+    let mut base = queue.swap(null_mut(), Ordering::Acquire);
+
+    while !base.is_null()
+    {
+        let next = (base as *const *mut u8).read();
+        pstd_mempool_free(base as *mut c_void);
+        base = next;
+    }
+}
+
+/**
+ * The global allocator backed by the Plumber memory pool.
+ *
+ * To use it as the allocator of the servlet:
+ *
+ * ```rust
+ *     #[global_allocator]
+ *     static ALLOCATOR : plumber_rs::mempool::PoolAllocator = plumber_rs::mempool::PoolAllocator;
+ * ```
+ *
+ * See the module documentation for when the system allocator is used instead.
+ **/
+pub struct PoolAllocator;
+
+unsafe impl GlobalAlloc for PoolAllocator {
+    unsafe fn alloc(&self, layout:Layout) -> *mut u8
+    {
+        if !tagged(&layout)
+        {
+            return System.alloc(layout);
+        }
+
+        let size = layout.size() + TAG_SIZE;
+        let mut tag = POOL_TAG;
+        let mut base = null_mut::<u8>();
+        let owner = if pool_ready() { pool_thread() } else { None };
+
+        if let Some(owner) = owner
+        {
+            free_deferred(owner);
+
+            let ptr = pstd_mempool_alloc(size as u32);
+
+            if !ptr.is_null() && !pool_aligned(ptr)
+            {
+                pstd_mempool_free(ptr);
+            }
+            else
+            {
+                base = ptr as *mut u8;
+            }
+        }
+
+        if base.is_null()
+        {
+            tag = SYSTEM_TAG;
+            base = System.alloc(Layout::from_size_align_unchecked(size, MEMPOOL_ALIGN));
+        }
+
+        if base.is_null()
+        {
+            return base;
+        }
+
+        (base as *mut usize).write(tag);
+        (base as *mut usize).add(1).write(owner.unwrap_or(0));
+        return base.add(TAG_SIZE);
+    }
+
+    unsafe fn dealloc(&self, ptr:*mut u8, layout:Layout)
+    {
+        if !tagged(&layout)
+        {
+            System.dealloc(ptr, layout);
+            return;
+        }
+
+        let base = ptr.sub(TAG_SIZE);
+
+        if (base as *const usize).read() == POOL_TAG
+        {
+            if !pool_finalized()
+            {
+                let owner = (base as *const usize).add(1).read();
+
+                if pool_thread() == Some(owner)
+                {
+                    free_deferred(owner);
+                    pstd_mempool_free(base as *mut c_void);
+                }
+                else
+                {
+                    defer_free(owner, base);
+                }
+            }
+            return;
+        }
+
+        System.dealloc(base, Layout::from_size_align_unchecked(layout.size() + TAG_SIZE, MEMPOOL_ALIGN));
+    }
+}
+
+/**
+ * A pointer type for heap allocation, whose memory comes from the Plumber memory pool.
+ *
+ * The box can't be sent to another thread, because the memory must be returned to the memory pool
+ * by the thread that allocated it.
+ **/
+pub struct PoolBox<T> {
+    ptr : NonNull<T>
+}
+
+impl <T> PoolBox<T> {
+    /**
+     * Move the value into the memory allocated from the memory pool
+     *
+     * * `value`: The value to move
+     *
+     * Returns the newly created box, or None when the memory pool isn't ready or can not allocate
+     * the properly aligned memory, or the type's alignment is larger than `MEMPOOL_ALIGN`
+     **/
+    pub fn new(value:T) -> Option<PoolBox<T>>
+    {
+        if !pool_servable(size_of::<T>(), align_of::<T>())
+        {
+            return None;
+        }
+
+        let ptr = if size_of::<T>() == 0
+        {
+            NonNull::dangling()
+        }
+        else if !pool_ready()
+        {
+            return None;
+        }
+        else if let Some(ptr) = NonNull::new(unsafe { pstd_mempool_alloc(size_of::<T>() as u32) } as *mut T)
+        {
+            if !pool_aligned(ptr.as_ptr() as *mut c_void)
+            {
+                unsafe { pstd_mempool_free(ptr.as_ptr() as *mut c_void) };
+                return None;
+            }
+            ptr
+        }
+        else
+        {
+            return None;
+        };
+
+        unsafe { ptr.as_ptr().write(value) };
+
+        return Some(PoolBox { ptr : ptr });
+    }
+
+    /**
+     * Move the value out of the box and return the memory to the memory pool
+     *
+     * * `this`: The box
+     *
+     * Returns the value
+     **/
+    pub fn into_inner(this:PoolBox<T>) -> T
+    {
+        let value = unsafe { this.ptr.as_ptr().read() };
+        unsafe { PoolBox::free(this.ptr) };
+        forget(this);
+        return value;
+    }
+
+    unsafe fn free(ptr:NonNull<T>)
+    {
+        if size_of::<T>() != 0 && !pool_finalized()
+        {
+            pstd_mempool_free(ptr.as_ptr() as *mut c_void);
+        }
+    }
+}
+
+impl <T> Deref for PoolBox<T> {
+    type Target = T;
+    fn deref(&self) -> &T
+    {
+        return unsafe { self.ptr.as_ref() };
+    }
+}
+
+impl <T> DerefMut for PoolBox<T> {
+    fn deref_mut(&mut self) -> &mut T
+    {
+        return unsafe { self.ptr.as_mut() };
+    }
+}
+
+impl <T> Drop for PoolBox<T> {
+    fn drop(&mut self)
+    {
+        unsafe {
+            drop_in_place(self.ptr.as_ptr());
+            PoolBox::free(self.ptr);
+        }
+    }
+}
+
+unsafe impl <T:Sync> Sync for PoolBox<T> {}
+
+/**
+ * An entire memory page allocated from the Plumber memory pool, which can't be sent to another
+ * thread like `PoolBox`
+ **/
+pub struct PoolPage {
+    ptr : NonNull<u8>
+}
+
+impl PoolPage {
+    /**
+     * Allocate a new page from the memory pool, the page is filled with zero
+     *
+     * Returns the newly allocated page or None if the memory pool isn't ready or on error
+     **/
+    pub fn new() -> Option<PoolPage>
+    {
+        if !pool_ready()
+        {
+            return None;
+        }
+
+        if let Some(ptr) = NonNull::new(unsafe { pstd_mempool_page_alloc() } as *mut u8)
+        {
+            unsafe { write_bytes(ptr.as_ptr(), 0, PoolPage::size()) };
+            return Some(PoolPage { ptr : ptr });
+        }
+        return None;
+    }
+
+    /**
+     * Get the size of the memory page
+     **/
+    pub fn size() -> usize
+    {
+        return unsafe { getpagesize() } as usize;
+    }
+}
+
+impl Deref for PoolPage {
+    type Target = [u8];
+    fn deref(&self) -> &[u8]
+    {
+        return unsafe { from_raw_parts(self.ptr.as_ptr(), PoolPage::size()) };
+    }
+}
+
+impl DerefMut for PoolPage {
+    fn deref_mut(&mut self) -> &mut [u8]
+    {
+        return unsafe { from_raw_parts_mut(self.ptr.as_ptr(), PoolPage::size()) };
+    }
+}
+
+impl Drop for PoolPage {
+    fn drop(&mut self)
+    {
+        if !pool_finalized()
+        {
+            unsafe { pstd_mempool_page_dealloc(self.ptr.as_ptr() as *mut c_void) };
+        }
+    }
+}
@@ -0,0 +1,381 @@
+// Copyright (C) 2018, Hao Hou
+
+//! The servlet metadata.
+//!
+//! The metadata of a Rust servlet is declared with the associated constants of its bootstrap type,
+//! and `export_bootstrap!` or `export_servlets!` exports it from the shared object as the
+//! `_rs_servlet_metadata` symbol. It's a `MetadataTable` that has one `ServletMetadata` for each
+//! servlet exported by the shared object, and all of them are plain data, so the tooling is able
+//! to read them without running the servlet.
+//!
+//! The Rust servlet loader fills the servlet definition it passes to the framework by itself, and
+//! the definition can't be changed by the servlet, so the metadata is only meant for the external
+//! tooling and it's not shown by the framework, for example in pscript.
+//!
+//! The pipes are taken from the pipe set declared with `pipe_set!`, so the schema always matches
+//! the pipes the servlet actually defines:
+//!
+//! ```rust
+//!     pipe_set! {
+//!         pipes ServletPipes {
+//!             "input"  [PIPE_INPUT]                : "plumber/std/request_local/String" => input  : Pipe<Input>;
+//!             "output" [PIPE_OUTPUT | PIPE_PERSIST] : "plumber/std/request_local/String" => output : Pipe<Output>;
+//!         }
+//!     }
+//!
+//!     impl Bootstrap for BootstrapType {
+//!         type SyncServletType = Servlet;
+//!         type AsyncServletType = Unimplemented;
+//!         const DESCRIPTION : &'static str = "Echo the input to the output";
+//!         const VERSION : u32 = 0x100;
+//!         const PIPES : &'static [PipeSchema] = ServletPipes::SCHEMA;
+//!         fn get(_args:&[&str]) -> BootstrapResult<Self> { /* ... */ }
+//!     }
+//! ```
+//!
+//! The pipe schema is a text of tab separated lines. The first line is the schema format header,
+//! followed by the description, the version and one line for each pipe:
+//!
+//! ```text
+//! schema      1
+//! description Echo the input to the output
+//! version     256
+//! pipe        input   input           plumber/std/request_local/String
+//! pipe        output  output persist  plumber/std/request_local/String
+//! ```
+//!
+//! The pipe line has the pipe name, the space separated pipe flags and the type expression, which
+//! is empty for an untyped pipe. The module specific flags are written as `module:<bits>`. Because
+//! of this format, the metadata strings can not contain tabs or new lines, which is checked at
+//! compile time.
+
+use crate::pipe::PipeFlags;
+
+use std::os::raw::c_char;
+
+/**
+ * The version of the pipe schema format
+ **/
+pub const SCHEMA_VERSION : u32 = 1;
+
+/**
+ * The version of the `MetadataTable` layout
+ **/
+pub const METADATA_FORMAT : u32 = 1;
+
+/**
+ * The names of the pipe flags in the schema, besides the direction
+ **/
+const FLAG_NAMES : [(PipeFlags, &'static str); 4] = [
+    (PipeFlags::PERSIST,  " persist"),
+    (PipeFlags::ASYNC,    " async"),
+    (PipeFlags::SHADOW,   " shadow"),
+    (PipeFlags::DISABLED, " disabled")
+];
+
+/**
+ * The schema of a pipe, which is usually generated by `pipe_set!`
+ **/
+pub struct PipeSchema {
+    /// The name of the pipe
+    pub name      : &'static str,
+    /// The pipe flags
+    pub flags     : PipeFlags,
+    /// The type expression, None for an untyped pipe
+    pub type_expr : Option<&'static str>
+}
+
+/**
+ * The metadata of a servlet exported by the shared object
+ **/
+#[repr(C)]
+pub struct ServletMetadata {
+    /// The NUL terminated servlet name used by `export_servlets!`, NULL for the only servlet
+    /// exported by `export_bootstrap!`
+    pub name    : *const c_char,
+    /// The NUL terminated servlet description
+    pub desc    : *const c_char,
+    /// The version of the servlet
+    pub version : u32,
+    /// The NUL terminated pipe schema of the servlet
+    pub schema  : *const c_char
+}
+
+/**
+ * The metadata of all the servlets exported by the shared object, which is exported as
+ * `_rs_servlet_metadata`
+ **/
+#[repr(C)]
+pub struct MetadataTable {
+    /// The layout version of the table, which is `METADATA_FORMAT`
+    pub format   : u32,
+    /// The number of the servlets
+    pub count    : u32,
+    /// The metadata of each servlet
+    pub servlets : *const ServletMetadata
+}
+
+// The metadata only points to the static data, so it's safe to share
+unsafe impl Sync for ServletMetadata {}
+unsafe impl Sync for MetadataTable {}
+
+/**
+ * Check if the string can be used in the servlet metadata
+ *
+ * * `s`: The string to check
+ *
+ * Returns the check result
+ **/
+pub const fn is_valid_metadata_str(s:&str) -> bool
+{
+    let bytes = s.as_bytes();
+    let mut idx = 0;
+    while idx < bytes.len()
+    {
+        if bytes[idx] == b'\t' || bytes[idx] == b'\n' || bytes[idx] == b'\r' || bytes[idx] == 0
+        {
+            return false;
+        }
+        idx += 1;
+    }
+    return true;
+}
+
+/**
+ * Convert the string to a NUL terminated byte array at compile time
+ *
+ * * `s`: The string to convert, `N` should be at least the length of the string plus one
+ *
+ * Returns the byte array
+ **/
+pub const fn to_c_bytes<const N:usize>(s:&str) -> [u8; N]
+{
+    let bytes = s.as_bytes();
+    let mut result = [0u8; N];
+    let mut idx = 0;
+    while idx < bytes.len() && idx + 1 < N
+    {
+        result[idx] = bytes[idx];
+        idx += 1;
+    }
+    return result;
+}
+
+/**
+ * Append the bytes to the buffer, the bytes that don't fit are counted but not written
+ *
+ * * `buf`: The buffer
+ * * `len`: The length of the text, including the bytes that don't fit
+ * * `bytes`: The bytes to append
+ **/
+const fn put(buf:&mut [u8], len:&mut usize, bytes:&[u8])
+{
+    let mut idx = 0;
+    while idx < bytes.len()
+    {
+        if *len < buf.len()
+        {
+            buf[*len] = bytes[idx];
+        }
+        *len += 1;
+        idx += 1;
+    }
+}
+
+/**
+ * Append the decimal representation of the number to the buffer
+ *
+ * * `buf`: The buffer
+ * * `len`: The length of the text
+ * * `value`: The number
+ **/
+const fn put_decimal(buf:&mut [u8], len:&mut usize, value:u32)
+{
+    let mut digits = [0u8; 10];
+    let mut count = 0;
+    let mut value = value;
+    loop
+    {
+        digits[count] = b'0' + (value % 10) as u8;
+        count += 1;
+        value /= 10;
+        if value == 0
+        {
+            break;
+        }
+    }
+    while count > 0
+    {
+        count -= 1;
+        put(buf, len, &[digits[count]]);
+    }
+}
+
+/**
+ * Render the pipe schema at compile time. It's called twice by `export_metadata!`, first with an
+ * empty buffer to get the length of the schema, then with a buffer which has the space for the
+ * schema and the trailing NUL.
+ *
+ * * `desc`: The servlet description
+ * * `version`: The servlet version
+ * * `pipes`: The pipes of the servlet
+ *
+ * Returns the buffer and the length of the schema
+ **/
+pub const fn render_schema<const N:usize>(desc:&str, version:u32, pipes:&[PipeSchema]) -> ([u8; N], usize)
+{
+    assert!(is_valid_metadata_str(desc), "The servlet description can not contain tabs or new lines");
+
+    let mut buf = [0u8; N];
+    let mut len = 0;
+
+    put(&mut buf, &mut len, b"schema\t");
+    put_decimal(&mut buf, &mut len, SCHEMA_VERSION);
+    put(&mut buf, &mut len, b"\ndescription\t");
+    put(&mut buf, &mut len, desc.as_bytes());
+    put(&mut buf, &mut len, b"\nversion\t");
+    put_decimal(&mut buf, &mut len, version);
+    put(&mut buf, &mut len, b"\n");
+
+    let mut idx = 0;
+    while idx < pipes.len()
+    {
+        let pipe = &pipes[idx];
+
+        assert!(is_valid_metadata_str(pipe.name), "The pipe name can not contain tabs or new lines");
+
+        put(&mut buf, &mut len, b"pipe\t");
+        put(&mut buf, &mut len, pipe.name.as_bytes());
+
+        if pipe.flags.contains(PipeFlags::OUTPUT)
+        {
+            put(&mut buf, &mut len, b"\toutput");
+        }
+        else
+        {
+            put(&mut buf, &mut len, b"\tinput");
+        }
+
+        let mut flag_idx = 0;
+        while flag_idx < FLAG_NAMES.len()
+        {
+            if pipe.flags.contains(FLAG_NAMES[flag_idx].0)
+            {
+                put(&mut buf, &mut len, FLAG_NAMES[flag_idx].1.as_bytes());
+            }
+            flag_idx += 1;
+        }
+
+        if pipe.flags.module_bits() != 0
+        {
+            put(&mut buf, &mut len, b" module:");
+            put_decimal(&mut buf, &mut len, pipe.flags.module_bits() as u32);
+        }
+
+        put(&mut buf, &mut len, b"\t");
+
+        if let Some(type_expr) = pipe.type_expr
+        {
+            assert!(is_valid_metadata_str(type_expr), "The type expression can not contain tabs or new lines");
+            put(&mut buf, &mut len, type_expr.as_bytes());
+        }
+
+        put(&mut buf, &mut len, b"\n");
+
+        idx += 1;
+    }
+
+    return (buf, len);
+}
+
+/**
+ * Export the metadata table of the servlets, this is used by `export_bootstrap!` and
+ * `export_servlets!`, so it shouldn't be used directly.
+ *
+ * Each servlet is either `Some(name) => bootstrap_type` or `None => bootstrap_type`.
+ **/
+#[doc(hidden)]
+#[macro_export]
+macro_rules! export_metadata {
+    (@servlet $name:expr, $bs:ty) => {{
+        const NAME : Option<&'static str> = $name;
+        const NAME_LEN : usize = match NAME { Some(name) => name.len() + 1, None => 0 };
+        const DESC : &'static str = <$bs as $crate::servlet::Bootstrap>::DESCRIPTION;
+        const VERSION : u32 = <$bs as $crate::servlet::Bootstrap>::VERSION;
+        const PIPES : &'static [$crate::metadata::PipeSchema] = <$bs as $crate::servlet::Bootstrap>::PIPES;
+        const SCHEMA_LEN : usize = $crate::metadata::render_schema::<0>(DESC, VERSION, PIPES).1;
+
+        static NAME_BYTES : [u8; NAME_LEN] = $crate::metadata::to_c_bytes(match NAME { Some(name) => name, None => "" });
+        static DESC_BYTES : [u8; DESC.len() + 1] = $crate::metadata::to_c_bytes(DESC);
+        static SCHEMA : [u8; SCHEMA_LEN + 1] = $crate::metadata::render_schema(DESC, VERSION, PIPES).0;
+
+        $crate::metadata::ServletMetadata {
+            name    : if NAME_LEN == 0 { ::std::ptr::null() } else { &NAME_BYTES as *const [u8; NAME_LEN] as *const ::std::os::raw::c_char },
+            desc    : &DESC_BYTES as *const [u8; DESC.len() + 1] as *const ::std::os::raw::c_char,
+            version : VERSION,
+            schema  : &SCHEMA as *const [u8; SCHEMA_LEN + 1] as *const ::std::os::raw::c_char
+        }
+    }};
+    ($($name:expr => $bs:ty),+) => {
+        #[allow(non_upper_case_globals)]
+        #[no_mangle]
+        pub static _rs_servlet_metadata : $crate::metadata::MetadataTable = {
+            static SERVLETS : [$crate::metadata::ServletMetadata; [$(stringify!($bs)),+].len()] = [$($crate::export_metadata!(@servlet $name, $bs)),+];
+
+            $crate::metadata::MetadataTable {
+                format   : $crate::metadata::METADATA_FORMAT,
+                count    : SERVLETS.len() as u32,
+                servlets : &SERVLETS as *const [$crate::metadata::ServletMetadata; [$(stringify!($bs)),+].len()] as *const $crate::metadata::ServletMetadata
+            }
+        };
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipe::{PIPE_INPUT, PIPE_OUTPUT, PIPE_PERSIST, PIPE_ASYNC};
+
+    const PIPES : [PipeSchema; 3] = [
+        PipeSchema { name : "input",  flags : PIPE_INPUT, type_expr : Some("plumber/std/request_local/String") },
+        PipeSchema { name : "output", flags : PIPE_OUTPUT.union(PIPE_PERSIST).union(PipeFlags::module(3)), type_expr : Some("float") },
+        PipeSchema { name : "log",    flags : PIPE_OUTPUT.union(PIPE_ASYNC), type_expr : None }
+    ];
+
+    #[test]
+    fn schema_has_a_line_for_each_pipe()
+    {
+        let (buf, len) = render_schema::<256>("Echo the input", 0x100, &PIPES[..]);
+
+        assert_eq!(std::str::from_utf8(&buf[..len]).unwrap(),
+                   "schema\t1\n\
+                    description\tEcho the input\n\
+                    version\t256\n\
+                    pipe\tinput\tinput\tplumber/std/request_local/String\n\
+                    pipe\toutput\toutput persist module:3\tfloat\n\
+                    pipe\tlog\toutput async\t\n");
+        assert_eq!(buf[len], 0);
+    }
+
+    #[test]
+    fn schema_length_is_counted_without_buffer()
+    {
+        let (_, len) = render_schema::<0>("", 0, &PIPES[..1]);
+        let (_, short_len) = render_schema::<16>("", 0, &PIPES[..1]);
+        let (buf, full_len) = render_schema::<128>("", 0, &PIPES[..1]);
+
+        assert_eq!(len, short_len);
+        assert_eq!(len, full_len);
+        assert_eq!(&buf[..len], &b"schema\t1\ndescription\t\nversion\t0\npipe\tinput\tinput\tplumber/std/request_local/String\n"[..]);
+    }
+
+    #[test]
+    fn metadata_strings_are_checked()
+    {
+        assert!(is_valid_metadata_str("Echo the input"));
+        assert!(!is_valid_metadata_str("Echo\tthe input"));
+        assert!(!is_valid_metadata_str("Echo\n"));
+        assert_eq!(to_c_bytes::<4>("echo"), *b"ech\0");
+        assert_eq!(to_c_bytes::<6>("echo"), *b"echo\0\0");
+    }
+}
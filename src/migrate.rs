@@ -0,0 +1,223 @@
+// Copyright (C) 2018, Hao Hou
+
+//! The versioned servlet state format for hot upgrades.
+//!
+//! When the Plumber application gets upgraded, the new version of the servlet binary is loaded
+//! and the old servlet instance is cleaned up. The Plumber runtime doesn't provide a way to pass
+//! data from the old servlet instance to the new one, so this crate doesn't hand the state over by
+//! itself. Until the runtime supports it, the servlet keeps the state in a storage of its own
+//! choice, for example a file, and this module defines the format of the state, so that a newer
+//! version of the servlet is able to read the state saved by an older one.
+//!
+//! Each version of the servlet may have a different state format, so the saved state carries the
+//! format version. The new version decides how to read the older formats, and falls back to a
+//! fresh state when it doesn't understand the format.
+//!
+//! ```rust
+//!     impl Migrate for Counter {
+//!         const VERSION : u32 = 2;
+//!         fn serialize(&self) -> Vec<u8> { /* ... */ }
+//!         fn deserialize(version:u32, data:&[u8]) -> Option<Counter>
+//!         {
+//!             match version {
+//!                 1 => /* Convert from the version 1 format */,
+//!                 2 => /* Read the current format */,
+//!                 _ => None
+//!             }
+//!         }
+//!     }
+//!
+//!     // In the cleanup function
+//!     fs::write(&self.state_path, MigratedState::save(&self.counter).to_bytes());
+//!
+//!     // In Bootstrap::get
+//!     let counter = fs::read(&state_path).ok()
+//!         .and_then(|bytes| MigratedState::from_bytes(&bytes[..]))
+//!         .and_then(|state| state.restore())
+//!         .unwrap_or_else(Counter::new);
+//! ```
+
+/**
+ * The magic number at the beginning of an encoded state
+ **/
+const STATE_MAGIC : &'static [u8; 4] = b"PRSM";
+
+/**
+ * The size of the header of an encoded state, which is the magic number and the format version
+ **/
+const STATE_HEADER_SIZE : usize = 8;
+
+/**
+ * The trait for a state that can be migrated to the next version of the servlet
+ **/
+pub trait Migrate : Sized {
+    /**
+     * The version of the state format produced by `serialize`
+     **/
+    const VERSION : u32;
+
+    /**
+     * Serialize the state
+     *
+     * Returns the serialized state in the format of `VERSION`
+     **/
+    fn serialize(&self) -> Vec<u8>;
+
+    /**
+     * Deserialize the state saved by either the current or a previous version of the servlet
+     *
+     * * `version`: The version of the state format
+     * * `data`: The serialized state
+     *
+     * Returns the state or None if the format is unknown or the data is invalid
+     **/
+    fn deserialize(version:u32, data:&[u8]) -> Option<Self>;
+}
+
+/**
+ * The serialized state handed over from the previous version of the servlet
+ **/
+pub struct MigratedState {
+    /// The version of the state format
+    version : u32,
+    /// The serialized state
+    data    : Vec<u8>
+}
+
+impl MigratedState {
+    /**
+     * Serialize the state, so that it can be handed over to the next version of the servlet
+     *
+     * * `state`: The state to save
+     *
+     * Returns the serialized state
+     **/
+    pub fn save<T:Migrate>(state:&T) -> MigratedState
+    {
+        return MigratedState {
+            version : T::VERSION,
+            data    : state.serialize()
+        };
+    }
+
+    /**
+     * Get the version of the state format
+     *
+     * Returns the format version
+     **/
+    pub fn version(&self) -> u32
+    {
+        return self.version;
+    }
+
+    /**
+     * Get the serialized state
+     *
+     * Returns the serialized state
+     **/
+    pub fn data(&self) -> &[u8]
+    {
+        return &self.data[..];
+    }
+
+    /**
+     * Restore the state
+     *
+     * Returns the restored state, or None if the format is unknown or the data is invalid
+     **/
+    pub fn restore<T:Migrate>(&self) -> Option<T>
+    {
+        return T::deserialize(self.version, &self.data[..]);
+    }
+
+    /**
+     * Encode the state as bytes, which carry the format version, so that the servlet is able to
+     * keep the state in any storage it chooses
+     *
+     * Returns the encoded state
+     **/
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        let mut bytes = Vec::with_capacity(STATE_HEADER_SIZE + self.data.len());
+        bytes.extend_from_slice(STATE_MAGIC);
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(&self.data[..]);
+        return bytes;
+    }
+
+    /**
+     * Decode the state encoded by `to_bytes`
+     *
+     * * `bytes`: The encoded state
+     *
+     * Returns the decoded state, or None if the bytes are not an encoded state
+     **/
+    pub fn from_bytes(bytes:&[u8]) -> Option<MigratedState>
+    {
+        if bytes.len() < STATE_HEADER_SIZE || &bytes[..STATE_MAGIC.len()] != STATE_MAGIC
+        {
+            return None;
+        }
+
+        let version = &bytes[STATE_MAGIC.len()..STATE_HEADER_SIZE];
+
+        return Some(MigratedState {
+            version : u32::from_le_bytes([version[0], version[1], version[2], version[3]]),
+            data    : bytes[STATE_HEADER_SIZE..].to_vec()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Migrate for u32 {
+        const VERSION : u32 = 2;
+        fn serialize(&self) -> Vec<u8> { return self.to_le_bytes().to_vec(); }
+        fn deserialize(version:u32, data:&[u8]) -> Option<u32>
+        {
+            match (version, data.len()) {
+                // The version 1 format is a single byte
+                (1, 1) => return Some(data[0] as u32),
+                (2, 4) => return Some(u32::from_le_bytes([data[0], data[1], data[2], data[3]])),
+                _      => return None
+            }
+        }
+    }
+
+    #[test]
+    fn state_survives_encoding()
+    {
+        let bytes = MigratedState::save(&0xdead_beefu32).to_bytes();
+        let state = MigratedState::from_bytes(&bytes[..]).expect("The state should be decoded");
+
+        assert_eq!(state.version(), 2);
+        assert_eq!(state.restore::<u32>(), Some(0xdead_beef));
+    }
+
+    #[test]
+    fn older_format_is_converted()
+    {
+        let mut bytes = b"PRSM".to_vec();
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.push(42);
+
+        let state = MigratedState::from_bytes(&bytes[..]).unwrap();
+        assert_eq!(state.restore::<u32>(), Some(42));
+    }
+
+    #[test]
+    fn unknown_format_falls_back()
+    {
+        let mut bytes = b"PRSM".to_vec();
+        bytes.extend_from_slice(&3u32.to_le_bytes());
+        bytes.extend_from_slice(&[1, 2, 3, 4]);
+
+        let state = MigratedState::from_bytes(&bytes[..]).unwrap();
+        assert_eq!(state.restore::<u32>().unwrap_or(0), 0);
+
+        assert!(MigratedState::from_bytes(b"PRS").is_none());
+        assert!(MigratedState::from_bytes(b"XXXX\x02\x00\x00\x00").is_none());
+    }
+}
@@ -0,0 +1,512 @@
+// Copyright (C) 2018, Hao Hou
+
+//! The servlet argument parser.
+//!
+//! This module is the binding for the libpstd option parser, so that a Rust servlet accepts the
+//! same option syntax as the native Plumber servlets, and gets the `--help` message for free.
+//!
+//! The options can be either parsed with the `OptionParser` directly, or declared as a typed
+//! config struct with the `servlet_options!` macro. A `#[derive(ServletOptions)]` would need a
+//! procedural macro crate, which this crate doesn't have, so the config struct is declared with
+//! the declarative macro instead:
+//!
+//! ```rust
+//!     servlet_options! {
+//!         options ServerConfig {
+//!             ["port", 'p'] : u16 = 8080 => port, "The port to listen";
+//!             ["verbose", 'v'] : bool = false => verbose, "Print more logs";
+//!             ["name"] : String = "server".to_string() => name, "The name of the server";
+//!         }
+//!     }
+//!
+//!     // In the servlet's init function
+//!     let (config, _) = ServerConfig::parse_options(args).map_err(|_| ())?;
+//! ```
+
+use crate::pstd::{
+    pstd_option_t,
+    pstd_option_data_t,
+    pstd_option_param_t,
+    pstd_option_parse,
+    pstd_option_sort,
+    pstd_option_handler_print_help,
+    pstd_option_param_type_t_PSTD_OPTION_TYPE_INT,
+    pstd_option_param_type_t_PSTD_OPTION_TYPE_DOUBLE,
+    pstd_option_param_type_t_PSTD_OPTION_STRING
+};
+
+use std::ffi::{CString, CStr};
+use std::os::raw::{c_char, c_int, c_void};
+use std::fmt::{Display, Formatter};
+use std::convert::TryFrom;
+
+/**
+ * A single value of the option parameter
+ **/
+#[derive(Debug, Clone, PartialEq)]
+pub enum OptionValue {
+    /// An integer parameter, which is declared with `I` in the pattern
+    Int(i64),
+    /// A floating point parameter, which is declared with `D` in the pattern
+    Double(f64),
+    /// A string parameter, which is declared with `S` in the pattern
+    Str(String)
+}
+
+/**
+ * The error type of the option parsing
+ **/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OptionError {
+    /// The `--help` option is given, the help message has been printed
+    HelpRequested,
+    /// The argument contains invalid chars, for example, a zero byte
+    InvalidArgument,
+    /// The option can not be passed to libpstd, because its short option isn't an ASCII char or
+    /// its long option, pattern or description contains a zero byte
+    InvalidOption(String),
+    /// The libpstd option parser is unable to parse the argument list
+    ParseFailure,
+    /// The parameter of the option can not be converted to the expected type
+    InvalidValue(String)
+}
+
+impl Display for OptionError {
+    fn fmt(&self, f:&mut Formatter) -> std::fmt::Result
+    {
+        return match self {
+            OptionError::HelpRequested      => write!(f, "help message requested"),
+            OptionError::InvalidArgument    => write!(f, "invalid argument"),
+            OptionError::InvalidOption(name)=> write!(f, "invalid option definition --{}", name),
+            OptionError::ParseFailure       => write!(f, "cannot parse the servlet arguments"),
+            OptionError::InvalidValue(name) => write!(f, "invalid value for option --{}", name)
+        };
+    }
+}
+
+impl std::error::Error for OptionError {}
+
+/**
+ * The description of a single option
+ **/
+struct OptionSpec {
+    /// The long option
+    long_opt    : CString,
+    /// The short option, zero for no short option
+    short_opt   : c_char,
+    /// The value pattern
+    pattern     : CString,
+    /// The description
+    description : CString
+}
+
+/**
+ * The option parser, which is the builder of the libpstd option array.
+ **/
+pub struct OptionParser {
+    /// All the options, the first one is always the help option
+    options : Vec<OptionSpec>
+}
+
+/**
+ * The result of the option parsing
+ **/
+pub struct ParsedOptions {
+    /// The long option name of each option
+    names     : Vec<String>,
+    /// The parameters of each option, None if the option is not given
+    values    : Vec<Option<Vec<OptionValue>>>,
+    /// The index of the first argument that hasn't been parsed
+    remaining : usize
+}
+
+impl ParsedOptions {
+    /**
+     * Get the parameters of the option.
+     *
+     * If the option is given multiple times, the last one will be returned.
+     *
+     * * `long_opt`: The long option name of the option
+     *
+     * Returns the parameter list or None if the option isn't given
+     **/
+    pub fn get(&self, long_opt:&str) -> Option<&[OptionValue]>
+    {
+        if let Some(idx) = self.names.iter().position(|name| name == long_opt)
+        {
+            if let Some(ref values) = self.values[idx]
+            {
+                return Some(&values[..]);
+            }
+        }
+        return None;
+    }
+
+    /**
+     * Check if the option is given in the argument list
+     *
+     * * `long_opt`: The long option name of the option
+     *
+     * Returns the check result
+     **/
+    pub fn is_present(&self, long_opt:&str) -> bool
+    {
+        return self.get(long_opt).is_some();
+    }
+
+    /**
+     * Get the index of the first argument that hasn't been parsed by the option parser
+     **/
+    pub fn remaining(&self) -> usize
+    {
+        return self.remaining;
+    }
+}
+
+/**
+ * The data passed to the option handlers during the parsing
+ **/
+struct ParseContext {
+    /// The parameters collected for each option
+    values : Vec<Option<Vec<OptionValue>>>,
+    /// If the help message has been printed
+    help   : bool
+}
+
+unsafe fn convert_params(data:&pstd_option_data_t) -> Option<Vec<OptionValue>>
+{
+    let mut result = Vec::new();
+
+    for idx in 0..data.param_array_size
+    {
+        let param:&pstd_option_param_t = &*data.param_array.offset(idx as isize);
+
+        let value = if param.type_ == pstd_option_param_type_t_PSTD_OPTION_TYPE_INT
+        {
+            OptionValue::Int(param.__bindgen_anon_1.intval)
+        }
+        else if param.type_ == pstd_option_param_type_t_PSTD_OPTION_TYPE_DOUBLE
+        {
+            OptionValue::Double(param.__bindgen_anon_1.doubleval)
+        }
+        else if param.type_ == pstd_option_param_type_t_PSTD_OPTION_STRING
+        {
+            if let Ok(string) = CStr::from_ptr(param.__bindgen_anon_1.strval).to_str()
+            {
+                OptionValue::Str(string.to_string())
+            }
+            else
+            {
+                return None;
+            }
+        }
+        else
+        {
+            return None;
+        };
+
+        result.push(value);
+    }
+
+    return Some(result);
+}
+
+unsafe extern "C" fn handle_option(data:pstd_option_data_t) -> c_int
+{
+    if let Some(ctx) = (data.cb_data as *mut ParseContext).as_mut()
+    {
+        // The options are sorted before parsing, so the index is carried by the option itself
+        let idx = match data.current_option.as_ref() {
+            Some(option) => option.args as usize,
+            None         => return -1
+        };
+
+        if idx < ctx.values.len()
+        {
+            if let Some(params) = convert_params(&data)
+            {
+                ctx.values[idx] = Some(params);
+                return 0;
+            }
+        }
+    }
+    return -1;
+}
+
+unsafe extern "C" fn handle_help(data:pstd_option_data_t) -> c_int
+{
+    if let Some(ctx) = (data.cb_data as *mut ParseContext).as_mut()
+    {
+        ctx.help = true;
+    }
+    return pstd_option_handler_print_help(data);
+}
+
+impl OptionParser {
+    /**
+     * Create a new option parser, which only contains the `--help` option
+     *
+     * Returns the newly created option parser
+     **/
+    pub fn new() -> OptionParser
+    {
+        let mut ret = OptionParser {
+            options : Vec::new()
+        };
+        // The help option is always valid
+        let _ = ret.add("help", Some('h'), "", "Print this help message");
+        return ret;
+    }
+
+    /**
+     * Add a new option to the parser.
+     *
+     * The pattern follows the libpstd convention: `I` for an integer, `D` for a double and `S` for
+     * a string, and the parameters are seperated with space. For example, `"I S"` means the option
+     * expects an integer and a string. An empty pattern means the option is a flag.
+     *
+     * * `long_opt`: The long option name, without the leading `--`
+     * * `short_opt`: The short option char, without the leading `-`
+     * * `pattern`: The value pattern
+     * * `description`: The description used in the help message
+     *
+     * Returns the parser itself, so the calls can be chained, or an error if the short option
+     * isn't a printable ASCII char or any of the strings contains a zero byte
+     **/
+    pub fn add(&mut self, long_opt:&str, short_opt:Option<char>, pattern:&str, description:&str) -> Result<&mut OptionParser, OptionError>
+    {
+        let short_opt = match short_opt {
+            Some(ch) if ch.is_ascii_graphic() => ch as u8 as c_char,
+            Some(_)                           => return Err(OptionError::InvalidOption(long_opt.to_string())),
+            None                              => 0
+        };
+
+        if let (Ok(c_long_opt), Ok(pattern), Ok(description)) = (CString::new(long_opt), CString::new(pattern), CString::new(description))
+        {
+            self.options.push(OptionSpec {
+                long_opt    : c_long_opt,
+                short_opt   : short_opt,
+                pattern     : pattern,
+                description : description
+            });
+            return Ok(self);
+        }
+
+        return Err(OptionError::InvalidOption(long_opt.to_string()));
+    }
+
+    /**
+     * Parse the servlet argument list.
+     *
+     * The argument list should be the one passed to the servlet, which means the first element is
+     * the servlet name, and it's not parsed as an option.
+     *
+     * * `args`: The servlet argument list
+     *
+     * Returns the parsed options or the error
+     **/
+    pub fn parse(&self, args:&[&str]) -> Result<ParsedOptions, OptionError>
+    {
+        let mut c_args = Vec::new();
+
+        for arg in args.iter()
+        {
+            if let Ok(c_arg) = CString::new(*arg)
+            {
+                c_args.push(c_arg);
+            }
+            else
+            {
+                return Err(OptionError::InvalidArgument);
+            }
+        }
+
+        let argv:Vec<*const c_char> = c_args.iter().map(|arg| arg.as_ptr()).collect();
+
+        let mut pstd_options:Vec<pstd_option_t> = self.options.iter().enumerate().map(|(idx, spec)| {
+            return pstd_option_t {
+                long_opt    : spec.long_opt.as_ptr(),
+                short_opt   : spec.short_opt,
+                pattern     : spec.pattern.as_ptr(),
+                description : spec.description.as_ptr(),
+                handler     : if idx == 0 { Some(handle_help) } else { Some(handle_option) },
+                args        : idx as *mut c_void
+            };
+        }).collect();
+
+        if unsafe { pstd_option_sort(pstd_options.as_mut_ptr(), pstd_options.len() as u32) } < 0
+        {
+            return Err(OptionError::ParseFailure);
+        }
+
+        let mut ctx = ParseContext {
+            values : vec![None; self.options.len()],
+            help   : false
+        };
+
+        let result = unsafe {
+            pstd_option_parse(pstd_options.as_ptr(),
+                              pstd_options.len() as u32,
+                              argv.len() as u32,
+                              argv.as_ptr(),
+                              &mut ctx as *mut ParseContext as *mut c_void)
+        };
+
+        if ctx.help
+        {
+            return Err(OptionError::HelpRequested);
+        }
+
+        if result == !0
+        {
+            return Err(OptionError::ParseFailure);
+        }
+
+        return Ok(ParsedOptions {
+            names     : self.options.iter().map(|spec| spec.long_opt.to_string_lossy().into_owned()).collect(),
+            values    : ctx.values,
+            remaining : result as usize
+        });
+    }
+}
+
+/**
+ * The trait for a Rust type that can be used as the value type of a typed servlet option
+ **/
+pub trait OptionValueType : Sized {
+    /**
+     * The libpstd value pattern for this type
+     **/
+    fn pattern() -> &'static str;
+
+    /**
+     * Convert the parsed parameters to the Rust value
+     *
+     * * `params`: The parameters of the option
+     *
+     * Returns the converted value or None if the parameters can not be converted
+     **/
+    fn from_params(params:&[OptionValue]) -> Option<Self>;
+}
+
+impl OptionValueType for bool {
+    fn pattern() -> &'static str { "" }
+    fn from_params(_params:&[OptionValue]) -> Option<bool> { Some(true) }
+}
+
+impl OptionValueType for String {
+    fn pattern() -> &'static str { "S" }
+    fn from_params(params:&[OptionValue]) -> Option<String>
+    {
+        if let Some(OptionValue::Str(ref val)) = params.first()
+        {
+            return Some(val.clone());
+        }
+        return None;
+    }
+}
+
+macro_rules! integer_option_type {
+    ($($type:ty),*) => {
+        $(impl OptionValueType for $type {
+            fn pattern() -> &'static str { "I" }
+            fn from_params(params:&[OptionValue]) -> Option<$type>
+            {
+                if let Some(OptionValue::Int(val)) = params.first()
+                {
+                    return <$type>::try_from(*val).ok();
+                }
+                return None;
+            }
+        })*
+    }
+}
+
+integer_option_type!(i8, i16, i32, i64, u8, u16, u32, u64, usize, isize);
+
+macro_rules! float_option_type {
+    ($($type:ty),*) => {
+        $(impl OptionValueType for $type {
+            fn pattern() -> &'static str { "D" }
+            fn from_params(params:&[OptionValue]) -> Option<$type>
+            {
+                return match params.first() {
+                    Some(OptionValue::Double(val)) => Some(*val as $type),
+                    Some(OptionValue::Int(val))    => Some(*val as $type),
+                    _ => None
+                };
+            }
+        })*
+    }
+}
+
+float_option_type!(f32, f64);
+
+/**
+ * The trait for a typed servlet config struct, which is usually implemented by the macro
+ * `servlet_options!`.
+ **/
+pub trait ServletOptions : Sized {
+    /**
+     * Parse the servlet argument list into the config struct
+     *
+     * * `args`: The servlet argument list
+     *
+     * Returns the config struct with the index of the first unparsed argument, or the error
+     **/
+    fn parse_options(args:&[&str]) -> Result<(Self, usize), OptionError>;
+}
+
+/**
+ * Declare a typed servlet config struct which is parsed from the servlet arguments.
+ *
+ * The syntax of each option is:
+ *
+ * ```
+ *  ["long-option", 's'] : rust_type = default_value => field_name, "description";
+ * ```
+ *
+ * The short option is optional. The value pattern is determined by the Rust type: `bool` is a
+ * flag, integer types use `I`, floating point types use `D` and `String` uses `S`.
+ * See the module documentation for an example.
+ **/
+#[macro_export]
+macro_rules! servlet_options {
+    ($(options $name:ident { $([$long:expr $(, $short:expr)?] : $type:ty = $default:expr => $field:ident, $desc:expr;)* })*) => {
+        $(
+        pub struct $name {
+            $(pub $field : $type,)*
+        }
+
+        impl $crate::options::ServletOptions for $name {
+            fn parse_options(args:&[&str]) -> Result<($name, usize), $crate::options::OptionError>
+            {
+                use $crate::options::{OptionParser, OptionValueType, OptionError};
+
+                let mut parser = OptionParser::new();
+
+                $(
+                    let short:Option<char> = None;
+                    $(let short = Some($short);)?
+                    parser.add($long, short, <$type as OptionValueType>::pattern(), $desc)?;
+                )*
+
+                let parsed = parser.parse(args)?;
+
+                let mut ret = $name {
+                    $($field : $default,)*
+                };
+
+                $(
+                    if let Some(params) = parsed.get($long)
+                    {
+                        ret.$field = <$type as OptionValueType>::from_params(params).ok_or(OptionError::InvalidValue($long.to_string()))?;
+                    }
+                )*
+
+                return Ok((ret, parsed.remaining()));
+            }
+        }
+        )*
+    }
+}
@@ -4,55 +4,351 @@
 //!
 //! This module is the wrapper to the actual Plumber pipe API calls for Pipe IO
 
-use crate::plumber_api::{runtime_api_pipe_t, runtime_api_pipe_flags_t};
+use crate::plumber_api::{runtime_api_pipe_t, runtime_api_pipe_flags_t, runtime_api_scope_token_t};
 use crate::plumber_api_call::get_cstr;
+use crate::metadata::PipeSchema;
+use crate::log::log_write;
 
 use std::io::{Read, Write, Result, Error, ErrorKind};
 use std::os::raw::c_void;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::{BitAnd, BitOr, BitOrAssign, Deref, DerefMut};
 
 use std::io::BufReader;
 
 /**
- * The integer type for the Plumber pipe flags
+ * The integer type used to represent a reference to the Pipe port
  **/
-pub type PipeFlags = runtime_api_pipe_flags_t;
+pub type PipeDescriptor = runtime_api_pipe_t;
 
 /**
- * The integer type used to represent a reference to the Pipe port
+ * The token of a request local scope (RLS) object
  **/
-pub type PipeDescriptor = runtime_api_pipe_t;
+pub type ScopeToken = runtime_api_scope_token_t;
+
+/**
+ * The Plumber pipe flags.
+ *
+ * The pipe flags is a 32 bit integer in the C API, which has the following layout:
+ *
+ * * Bit 0 - 15: The source pipe of a shadow output, which is only meaningful with `SHADOW`
+ * * Bit 16 - 23: The flags defined by the framework, for example, `OUTPUT` and `PERSIST`
+ * * Bit 24 - 31: The module specific flags, which are interpreted by the IO module
+ *
+ * The flags can be combined with `|`, and the combinations that make no sense are rejected by
+ * `Pipe::define`, `Pipe::set_flags` and `Pipe::clear_flags`. See `is_valid` for details.
+ **/
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct PipeFlags(runtime_api_pipe_flags_t);
+
+impl PipeFlags {
+    /// Indicates the pipe port is an input side
+    pub const INPUT    : PipeFlags = PipeFlags(0);
+    /// Indicates the pipe port is an output side
+    pub const OUTPUT   : PipeFlags = PipeFlags(0x10000);
+    /// Suggest the framework to keep the communication resource for more events
+    pub const PERSIST  : PipeFlags = PipeFlags(0x20000);
+    /// Suggest the framework to use the async write thread if possible
+    pub const ASYNC    : PipeFlags = PipeFlags(0x40000);
+    /// The output pipe is a copy of an input pipe
+    pub const SHADOW   : PipeFlags = PipeFlags(0x80000);
+    /// Do not forward data to the shadow output
+    pub const DISABLED : PipeFlags = PipeFlags(0x100000);
+
+    /// The bits that carries the source pipe of a shadow output
+    const TARGET_MASK  : runtime_api_pipe_flags_t = 0xffff;
+    /// The bits of the flags defined by the framework
+    const GENERIC_MASK : runtime_api_pipe_flags_t = 0xff0000;
+    /// The bits of the module specific flags
+    const MODULE_MASK  : runtime_api_pipe_flags_t = 0xff000000;
+    /// The offset of the module specific flags
+    const MODULE_SHIFT : u32 = 24;
+    /// All the flags defined by the framework
+    const KNOWN_FLAGS  : runtime_api_pipe_flags_t = 0x1f0000;
+    /// The flags that can be changed during the execution stage
+    const RUNTIME_FLAGS: runtime_api_pipe_flags_t = 0x160000;
+
+    /**
+     * Create an empty pipe flags, which is the same as `INPUT`
+     **/
+    pub const fn empty() -> PipeFlags
+    {
+        return PipeFlags(0);
+    }
+
+    /**
+     * Create the pipe flags from the raw integer
+     *
+     * * `bits`: The raw pipe flags
+     *
+     * Returns the pipe flags, or None if it's not a valid combination
+     **/
+    pub fn from_bits(bits:runtime_api_pipe_flags_t) -> Option<PipeFlags>
+    {
+        let flags = PipeFlags(bits);
+        if flags.is_valid()
+        {
+            return Some(flags);
+        }
+        return None;
+    }
+
+    /**
+     * Create the pipe flags from the raw integer without any check. This is useful when the flags
+     * is returned by the framework.
+     *
+     * * `bits`: The raw pipe flags
+     *
+     * Returns the pipe flags
+     **/
+    pub const fn from_bits_retain(bits:runtime_api_pipe_flags_t) -> PipeFlags
+    {
+        return PipeFlags(bits);
+    }
+
+    /**
+     * Get the raw integer of the pipe flags
+     **/
+    pub const fn bits(self) -> runtime_api_pipe_flags_t
+    {
+        return self.0;
+    }
+
+    /**
+     * Combine two pipe flags, this is the const version of `|`
+     **/
+    pub const fn union(self, other:PipeFlags) -> PipeFlags
+    {
+        return PipeFlags(self.0 | other.0);
+    }
+
+    /**
+     * Check if all the flags in `other` are set
+     **/
+    pub const fn contains(self, other:PipeFlags) -> bool
+    {
+        return (self.0 & other.0) == other.0;
+    }
+
+    /**
+     * Create the flags of a shadow output of the given source pipe
+     *
+     * * `target`: The source pipe
+     *
+     * Returns the flags, or None if the pipe descriptor can not be encoded in the flags
+     **/
+    pub fn shadow_of(target:PipeDescriptor) -> Option<PipeFlags>
+    {
+        if target & !Self::TARGET_MASK != 0
+        {
+            return None;
+        }
+        return Some(PipeFlags(Self::OUTPUT.0 | Self::SHADOW.0 | target));
+    }
+
+    /**
+     * Get the source pipe of a shadow output
+     *
+     * Returns the source pipe, or None if the flags isn't a shadow output
+     **/
+    pub fn target(self) -> Option<PipeDescriptor>
+    {
+        if self.contains(Self::SHADOW)
+        {
+            return Some(self.0 & Self::TARGET_MASK);
+        }
+        return None;
+    }
+
+    /**
+     * Create the pipe flags that only has the module specific flags
+     *
+     * * `bits`: The module specific flags, which are the bit 24 - 31 of the pipe flags
+     *
+     * Returns the pipe flags
+     **/
+    pub const fn module(bits:u8) -> PipeFlags
+    {
+        return PipeFlags((bits as runtime_api_pipe_flags_t) << Self::MODULE_SHIFT);
+    }
+
+    /**
+     * Get the module specific flags
+     **/
+    pub const fn module_bits(self) -> u8
+    {
+        return ((self.0 & Self::MODULE_MASK) >> Self::MODULE_SHIFT) as u8;
+    }
+
+    /**
+     * Check if the pipe flags is a valid combination to define a pipe, which means:
+     *
+     * * There's no undefined flags
+     * * The source pipe is only set for a shadow output
+     * * `ASYNC`, `SHADOW` and `DISABLED` are only set for an output
+     * * `DISABLED` is only set for a shadow output
+     *
+     * Returns the check result
+     **/
+    pub fn is_valid(self) -> bool
+    {
+        if self.0 & Self::GENERIC_MASK & !Self::KNOWN_FLAGS != 0
+        {
+            return false;
+        }
+
+        if self.0 & Self::TARGET_MASK != 0 && !self.contains(Self::SHADOW)
+        {
+            return false;
+        }
+
+        if !self.contains(Self::OUTPUT) && self.0 & (Self::ASYNC.0 | Self::SHADOW.0 | Self::DISABLED.0) != 0
+        {
+            return false;
+        }
+
+        return !self.contains(Self::DISABLED) || self.contains(Self::SHADOW);
+    }
+
+    /**
+     * Check if the pipe flags only has the flags that can be changed during the execution stage,
+     * which are `PERSIST`, `ASYNC`, `DISABLED` and the module specific flags.
+     *
+     * Returns the check result
+     **/
+    pub fn is_runtime(self) -> bool
+    {
+        return self.0 & !(Self::RUNTIME_FLAGS | Self::MODULE_MASK) == 0;
+    }
+}
+
+impl BitOr for PipeFlags {
+    type Output = PipeFlags;
+    fn bitor(self, other:PipeFlags) -> PipeFlags
+    {
+        return self.union(other);
+    }
+}
+
+impl BitOrAssign for PipeFlags {
+    fn bitor_assign(&mut self, other:PipeFlags)
+    {
+        self.0 |= other.0;
+    }
+}
+
+impl BitAnd for PipeFlags {
+    type Output = PipeFlags;
+    fn bitand(self, other:PipeFlags) -> PipeFlags
+    {
+        return PipeFlags(self.0 & other.0);
+    }
+}
+
+impl Debug for PipeFlags {
+    fn fmt(&self, f:&mut Formatter) -> std::fmt::Result
+    {
+        let names = [(PipeFlags::PERSIST, "PERSIST"), (PipeFlags::ASYNC, "ASYNC"), (PipeFlags::SHADOW, "SHADOW"), (PipeFlags::DISABLED, "DISABLED")];
+        let unknown = self.0 & PipeFlags::GENERIC_MASK & !PipeFlags::KNOWN_FLAGS;
+
+        write!(f, "PipeFlags({}", if self.contains(PipeFlags::OUTPUT) { "OUTPUT" } else { "INPUT" })?;
+
+        for &(flag, name) in names.iter()
+        {
+            if self.contains(flag)
+            {
+                write!(f, " | {}", name)?;
+            }
+        }
 
-// TODO: Currently because of the limit of rust-bindgen, all the constant marcos with non-primitive
-//       type is missing in the bind file. So we have do define it manually
+        if unknown != 0
+        {
+            write!(f, " | {:#x}", unknown)?;
+        }
+
+        if self.0 & PipeFlags::TARGET_MASK != 0 || self.contains(PipeFlags::SHADOW)
+        {
+            write!(f, ", target: {}", self.0 & PipeFlags::TARGET_MASK)?;
+        }
+
+        if self.module_bits() != 0
+        {
+            write!(f, ", module: {:#x}", self.module_bits())?;
+        }
+
+        return write!(f, ")");
+    }
+}
 
 /**
  * Indicates the pipe port is an input side
  **/
-pub const PIPE_INPUT    :PipeFlags   = 0;
+pub const PIPE_INPUT    :PipeFlags   = PipeFlags::INPUT;
 /**
  * Indictes the pipe port is an output  side
  **/
-pub const PIPE_OUTPUT   :PipeFlags   = 0x10000;
+pub const PIPE_OUTPUT   :PipeFlags   = PipeFlags::OUTPUT;
 /**
  * If this flag is set it suggest the Plumber framework to keep the communication resource for more
  * event even after current resource has been processed
  **/
-pub const PIPE_PERSIST  :PipeFlags   = 0x20000;
+pub const PIPE_PERSIST  :PipeFlags   = PipeFlags::PERSIST;
 /**
  * If this flag is set, it suggest Plumber framework use the async write thread if possible. This
  * is typically useful when we want to write a large file
  **/
-pub const PIPE_ASYNC    :PipeFlags   = 0x40000;
+pub const PIPE_ASYNC    :PipeFlags   = PipeFlags::ASYNC;
 /**
  * This flag makes the output pipe a copy of input pipe. This is also called a fork, which split
- * the dataflow into multiple ways.
+ * the dataflow into multiple ways. The source input pipe is encoded in the lower bits of the
+ * flags, use `Pipe::define_shadow` to define a shadow output.
  **/
-pub const PIPE_SHADOW   :PipeFlags   = 0x80000;
+pub const PIPE_SHADOW   :PipeFlags   = PipeFlags::SHADOW;
 /**
  * The pipe is diable, which is only meaningful when the pipe is a fork of another pipe. It
  * indicates do not forward data to this fork
  **/
-pub const PIPE_DISABLED :PipeFlags   = 0x100000;
+pub const PIPE_DISABLED :PipeFlags   = PipeFlags::DISABLED;
+
+/**
+ * The trait for the type level direction of a pipe port, which is either `Input` or `Output`
+ **/
+pub trait PipeDirection {
+    /// The direction flag used to define the pipe, either `PIPE_INPUT` or `PIPE_OUTPUT`
+    const DIRECTION_FLAG : PipeFlags;
+    /// The pipe flags that are allowed for a pipe of this direction
+    const ALLOWED_FLAGS  : PipeFlags;
+}
+
+/**
+ * The direction of an input pipe port, which can only be read
+ **/
+pub struct Input;
+
+/**
+ * The direction of an output pipe port, which can only be written
+ **/
+pub struct Output;
+
+impl PipeDirection for Input {
+    const DIRECTION_FLAG : PipeFlags = PIPE_INPUT;
+    const ALLOWED_FLAGS  : PipeFlags = PIPE_PERSIST;
+}
+
+impl PipeDirection for Output {
+    const DIRECTION_FLAG : PipeFlags = PIPE_OUTPUT;
+    const ALLOWED_FLAGS  : PipeFlags = PIPE_PERSIST.union(PIPE_ASYNC).union(PIPE_SHADOW).union(PIPE_DISABLED);
+}
+
+/**
+ * Check if the framework defined flags are allowed for the pipe direction
+ **/
+fn flags_allowed<D:PipeDirection>(flags:PipeFlags) -> bool
+{
+    let allowed = D::DIRECTION_FLAG.union(D::ALLOWED_FLAGS).bits();
+    return flags.bits() & PipeFlags::KNOWN_FLAGS & !allowed == 0;
+}
 
 const PIPE_CNTL_GET_FLAGS:u32        = crate::plumber_api::RUNTIME_API_PIPE_CNTL_OPCODE_GET_FLAGS;
 const PIPE_CNTL_SET_FLAG:u32         = crate::plumber_api::RUNTIME_API_PIPE_CNTL_OPCODE_SET_FLAG;
@@ -107,17 +403,61 @@ macro_rules! pipe_cntl {
  * port when we write the servlet. This is called `pipe_t` in the C API. However, In rust, we
  * implemented the Pipe object which allows us directly read and write the pipe with the object.
  *
+ * The direction of the pipe port is a part of the type, so only an input pipe can be read and
+ * only an output pipe can be written.
+ *
+ * * `D`: The direction of the pipe port, either `Input` or `Output`
  * * `ST`: The type of the state. This is only used when we want to implement a stateful port
  **/
 #[allow(dead_code)]
-pub struct Pipe<ST> {
+pub struct Pipe<D:PipeDirection, ST = ()> {
     /// The actual pipe descriptor
     pipe : runtime_api_pipe_t,
     /// The phantom data
-    _st  : crate::std::marker::PhantomData<ST>
+    _st  : crate::std::marker::PhantomData<(D, ST)>
 }
 
 
+/**
+ * The mutable reference to the state attached to the pipe resource, which is returned by
+ * `Pipe::state_mut`.
+ *
+ * The guard borrows the pipe, so the state can only be modified during the current activation.
+ * When the guard is dropped, `PIPE_PERSIST` is set for the pipe, so that the state is kept for the
+ * next activation. Use `Pipe::take_state` to release the communication resource.
+ **/
+pub struct StateGuard<'a, D:PipeDirection + 'a, ST:'a> {
+    /// The pipe that owns the state
+    pipe  : &'a mut Pipe<D, ST>,
+    /// The state
+    state : &'a mut ST
+}
+
+impl <'a, D:PipeDirection, ST> Deref for StateGuard<'a, D, ST> {
+    type Target = ST;
+    fn deref(&self) -> &ST
+    {
+        return self.state;
+    }
+}
+
+impl <'a, D:PipeDirection, ST> DerefMut for StateGuard<'a, D, ST> {
+    fn deref_mut(&mut self) -> &mut ST
+    {
+        return self.state;
+    }
+}
+
+impl <'a, D:PipeDirection, ST> Drop for StateGuard<'a, D, ST> {
+    fn drop(&mut self)
+    {
+        if let None = self.pipe.set_flags(PIPE_PERSIST)
+        {
+            log_write(1, file!(), line!() as i32, "Cannot set PIPE_PERSIST for the pipe with state attached");
+        }
+    }
+}
+
 /**
  * A reference to a given pipe port.
  *
@@ -147,21 +487,40 @@ impl Read for PipeRef {
     }
 }
 
-impl <ST> Pipe<ST> {
+/**
+ * The trait for the pipe types that can be declared in `pipe_set!`, which tells the direction of
+ * the pipe from its type
+ **/
+pub trait PipeKind {
+    /// The direction flag of the pipe, either `PIPE_INPUT` or `PIPE_OUTPUT`
+    const DIRECTION_FLAG : PipeFlags;
+}
 
-    /**
-     * Get a `std::io::BufReader` object from current pipe port.
-     *
-     * This is useful when we want to do text IO to the pipe
-     *
-     * Returns the ownership of the newly created reader
-     **/
-    pub fn as_bufreader(&self) -> BufReader<PipeRef>
-    {
-        return BufReader::new(PipeRef {
-            pipe : self.pipe
-        });
-    }
+impl <D:PipeDirection, ST> PipeKind for Pipe<D, ST> {
+    const DIRECTION_FLAG : PipeFlags = D::DIRECTION_FLAG;
+}
+
+/**
+ * Check a flag declared in `pipe_set!` against the direction of the pipe type, this is evaluated
+ * at compile time so that a conflicting direction flag is a compile error.
+ *
+ * `PIPE_INPUT` doesn't have any bit set, so it doesn't change the flags of an output pipe and only
+ * `PIPE_OUTPUT` can conflict with the pipe direction.
+ *
+ * * `flag`: The declared flag
+ *
+ * Returns the declared flag
+ **/
+pub const fn declared_flag<P:PipeKind>(flag:PipeFlags) -> PipeFlags
+{
+    let is_output = P::DIRECTION_FLAG.bits() & PIPE_OUTPUT.bits() != 0;
+
+    assert!(is_output || flag.bits() & PIPE_OUTPUT.bits() == 0, "PIPE_OUTPUT is declared for an input pipe");
+
+    return flag;
+}
+
+impl <D:PipeDirection, ST> Pipe<D, ST> {
 
     /**
      * Get the actual pipe descriptor managed by this pipe object
@@ -181,20 +540,28 @@ impl <ST> Pipe<ST> {
      * from execution or cleanup stage, the result will be a failure.
      *
      * * `name` The name of the port. It will be used for the dataflow graph construction
-     * * `flags` The initial pipe flag of this pipe. 
+     * * `flags` The initial pipe flag of this pipe. The direction flag is added according to the
+     * pipe direction, and the flags that are not allowed for the direction cause a failure.
      * * `type_expr` The type expression for the protocol of this pipe port. See Plumber's protocol
      * typing documentations for detail.
      *
      * Returns either `None` on creating failure or `Some` of ownership of the newly created pipe
      * object
      **/
-    pub fn define(name:&str, flags: PipeFlags, type_expr:Option<&str>) -> Option<Pipe<ST>>
+    pub fn define(name:&str, flags: PipeFlags, type_expr:Option<&str>) -> Option<Pipe<D, ST>>
     {
+        let flags = flags | D::DIRECTION_FLAG;
+
+        if !flags.is_valid() || !flags_allowed::<D>(flags)
+        {
+            return None;
+        }
+
         let (name_ptr, _name) = get_cstr(Some(name));
         let (type_ptr, _type) = get_cstr(type_expr);
 
         plumber_api_call!{
-            let result = define(name_ptr, flags, type_ptr) in {
+            let result = define(name_ptr, flags.bits(), type_ptr) in {
                 if result as i32 != -1
                 {
                     return Some(Pipe{pipe : result, _st : ::std::marker::PhantomData});
@@ -205,40 +572,6 @@ impl <ST> Pipe<ST> {
         return None;
     }
 
-    /**
-     * Check if the pipe contains no more data. 
-     *
-     * This is meaningful only when we are currently executing some execution task with this servlet. 
-     * Which means it only can be called from either `exec` and `async_init`, `async_cleanup` stage
-     * of a servlet. Otherwise it will returns a failure.
-     *
-     * The EOF function in Plumber defines a little bit different from normal EOF. It indicates if
-     * it's possible to have further data.
-     *
-     * If this function returns `true`, it's possible we have more data in the furture, but it's **not** 
-     * means we current have data to read. It's also possible that there's no more data but the
-     * framework is not able to realize that currently. 
-     *
-     * If this function returns `false`, it indicates there are definitely no more data can be read
-     * from this port. 
-     *
-     * Returns either None on error case or the check result
-     **/
-    pub fn eof(&mut self) -> Option<bool>
-    {
-        plumber_api_call!{
-            let result = eof(self.pipe) in {
-                if result as i32 != -1
-                {
-                    return Some(result > 0);
-                }
-                return None;
-            }
-        }
-
-        return None;
-    }
-
     /**
      * Get the runtime flags of this port. 
      *
@@ -249,12 +582,12 @@ impl <ST> Pipe<ST> {
      **/
     pub fn flags(&mut self) -> Option<PipeFlags> 
     {
-        let mut pf = 0 as PipeFlags;
-        let pf_ref = &mut pf as *mut PipeFlags;
+        let mut pf = 0 as runtime_api_pipe_flags_t;
+        let pf_ref = &mut pf as *mut runtime_api_pipe_flags_t;
 
         if -1 != pipe_cntl!(self.pipe, PIPE_CNTL_GET_FLAGS, pf_ref as *mut c_void)
         {
-            return Some(pf);
+            return Some(PipeFlags::from_bits_retain(pf));
         }
         return None;
     } 
@@ -268,7 +601,7 @@ impl <ST> Pipe<ST> {
     {
         if let Some(result) = self.flags()
         {
-            return Some((result & flag) == flag);
+            return Some(result.contains(flag));
         }
         return None;
     }
@@ -276,13 +609,14 @@ impl <ST> Pipe<ST> {
     /**
      * Set the runtime flags of the pipe port 
      *
-     * * `flag` The pipe flag we want to add to the pipe
+     * * `flag` The pipe flag we want to add to the pipe, which must be allowed for the pipe
+     * direction and can be changed during the execution stage, see `PipeFlags::is_runtime`
      *
      * Return the operation result `None` indicates failure, `Some` Indicates success
      **/
     pub fn set_flags(&mut self, flag:PipeFlags) -> Option<()>
     {
-        if -1 != pipe_cntl!(self.pipe, PIPE_CNTL_SET_FLAG, flag)
+        if flag.is_runtime() && flags_allowed::<D>(flag) && -1 != pipe_cntl!(self.pipe, PIPE_CNTL_SET_FLAG, flag.bits())
         {
             return Some(());
         }
@@ -292,13 +626,14 @@ impl <ST> Pipe<ST> {
     /**
      * Unset the runtime flags for a pipe port
      *
-     * * `flag` The pipe flag we want to unset
+     * * `flag` The pipe flag we want to unset, which must be allowed for the pipe direction and
+     * can be changed during the execution stage, see `PipeFlags::is_runtime`
      *
      * Return the operation result `None` indicates failure, `Some` for success
      **/
     pub fn clear_flags(&mut self, flag:PipeFlags) -> Option<()>
     {
-        if -1 != pipe_cntl!(self.pipe, PIPE_CNTL_CLR_FLAG, flag)
+        if flag.is_runtime() && flags_allowed::<D>(flag) && -1 != pipe_cntl!(self.pipe, PIPE_CNTL_CLR_FLAG, flag.bits())
         {
             return Some(());
         }
@@ -307,10 +642,35 @@ impl <ST> Pipe<ST> {
 
     extern "C" fn dispose_state(ptr : *mut c_void) -> i32
     {
-        unsafe { Box::from_raw(ptr as *mut ST) };
+        drop(unsafe { Box::from_raw(ptr as *mut Option<ST>) });
         return 0;
     }
 
+    /**
+     * Get the state cell attached to the current pipe resource.
+     *
+     * The state is pushed to the framework as a cell which is either empty or holding the state,
+     * so that the servlet is able to take the ownership of the state back, while the cell itself
+     * is still owned by the framework.
+     *
+     * Returns the pointer to the state cell or None if there's no state attached
+     **/
+    fn state_cell(&mut self) -> Option<*mut Option<ST>>
+    {
+        let state_ptr = ::std::ptr::null::<Option<ST>>() as *mut Option<ST>;
+
+        let state_ptr_ref = &state_ptr;
+
+        if -1 != pipe_cntl!(self.pipe, PIPE_CNTL_POP_STATE, state_ptr_ref as *const *mut Option<ST>)
+        {
+            if !state_ptr.is_null()
+            {
+                return Some(state_ptr);
+            }
+        }
+        return None;
+    }
+
     /**
      * Get the associated state for current pipe resource.
      *
@@ -324,35 +684,95 @@ impl <ST> Pipe<ST> {
      * Returns the retrieved reference to the Obect.
      *
      * Note: Plumber framework always manage the ownership of the pushed state objects. So in this
-     * function only a reference will be returned. All the memory management is done by Plumber
-     * rather than Rust.
+     * function only a reference will be returned, which is valid as long as the pipe isn't touched.
+     * Use `state_mut` to modify the state and `take_state` to get the ownership back.
      *
      **/
-    pub fn get_state<'a>(&mut self) -> Option<&'a ST>
+    pub fn get_state(&mut self) -> Option<&ST>
     {
-        let state_ptr = ::std::ptr::null::<ST>() as *mut ST;
-
-        let state_ptr_ref = &state_ptr;
+        if let Some(cell) = self.state_cell()
+        {
+            return unsafe { (*cell).as_ref() };
+        }
+        return None;
+    }
 
-        if -1 != pipe_cntl!(self.pipe, PIPE_CNTL_POP_STATE, state_ptr_ref as *const *mut ST)
+    /**
+     * Get the mutable reference to the associated state for current pipe resource.
+     *
+     * The returned guard borrows the pipe, so it can't outlive the current activation. When the
+     * guard is dropped, `PIPE_PERSIST` is set, so that the state is available when the servlet is
+     * activated by the same communication resource again.
+     *
+     * Returns the guard of the state or None if there's no state attached
+     **/
+    pub fn state_mut<'a>(&'a mut self) -> Option<StateGuard<'a, D, ST>>
+    {
+        if let Some(cell) = self.state_cell()
         {
-            if let Some(state) = unsafe{ state_ptr.as_ref() }
+            if let Some(state) = unsafe { (*cell).as_mut() }
             {
-                return Some(state);
+                return Some(StateGuard {
+                    pipe  : self,
+                    state : state
+                });
             }
         }
         return None;
     }
 
+    /**
+     * Get the mutable reference to the associated state, or attach a new state to the pipe
+     * resource if there's no state attached. See `state_mut` for details.
+     *
+     * * `init`: The function that creates the new state
+     *
+     * Returns the guard of the state or None on error
+     **/
+    pub fn state_mut_or_insert_with<'a, F:FnOnce() -> ST>(&'a mut self, init:F) -> Option<StateGuard<'a, D, ST>>
+    {
+        match self.state_cell() {
+            Some(cell) => {
+                let cell = unsafe { &mut *cell };
+                if cell.is_none()
+                {
+                    *cell = Some(init());
+                }
+            },
+            None => self.push_state(Box::new(init()))?
+        }
+        return self.state_mut();
+    }
+
+    /**
+     * Take the ownership of the associated state back from the pipe resource.
+     *
+     * This means the servlet doesn't want the communication resource anymore, so `PIPE_PERSIST`
+     * is cleared even if there's no state attached.
+     *
+     * Returns the state or None if there's no state attached
+     **/
+    pub fn take_state(&mut self) -> Option<ST>
+    {
+        let _ = self.clear_flags(PIPE_PERSIST);
+
+        if let Some(cell) = self.state_cell()
+        {
+            return unsafe { (*cell).take() };
+        }
+        return None;
+    }
+
     /**
      * Push the state object to the pipe. This will attach the state to the pipe communication
-     * resources. 
+     * resources, and set `PIPE_PERSIST` for the pipe.
      *
      * See the documentation of `get_state` for more detailed description of state mechanism.
      *
      * * `obj`: The box that contains the ownership of the state we want to push
      *
-     * Return The operation result.
+     * Return The operation result. The state is attached even if `PIPE_PERSIST` can't be set,
+     * which is logged, and `check_flag` tells if the pipe resource will be kept.
      *
      * Note: This function always takes the ownership of the state object, even if it returns a
      * failure. 
@@ -361,15 +781,19 @@ impl <ST> Pipe<ST> {
     {
         let dispose_func_ptr = Self::dispose_state as *const c_void;
 
-        let box_ref = Box::leak(obj);
+        let box_ref = Box::leak(Box::new(Some(*obj)));
 
-        let box_ptr = box_ref as *mut ST;
+        let box_ptr = box_ref as *mut Option<ST>;
 
         let void_ptr = box_ptr as *mut c_void;
 
 
         if -1 != pipe_cntl!(self.pipe, PIPE_CNTL_PUSH_STATE, void_ptr, dispose_func_ptr)
         {
+            if let None = self.set_flags(PIPE_PERSIST)
+            {
+                log_write(1, file!(), line!() as i32, "Cannot set PIPE_PERSIST for the pipe with state attached");
+            }
             return Some(());
         }
         
@@ -380,7 +804,163 @@ impl <ST> Pipe<ST> {
 
 }
 
-impl <ST> Read for Pipe<ST> {
+impl <ST> Pipe<Input, ST> {
+
+    /**
+     * Get a `std::io::BufReader` object from current pipe port.
+     *
+     * This is useful when we want to do text IO to the pipe
+     *
+     * Returns the ownership of the newly created reader
+     **/
+    pub fn as_bufreader(&self) -> BufReader<PipeRef>
+    {
+        return BufReader::new(PipeRef {
+            pipe : self.pipe
+        });
+    }
+
+    /**
+     * Get a reference to current pipe port, which can be read without borrowing the pipe object.
+     *
+     * Returns the newly created reference
+     **/
+    pub fn as_pipe_ref(&self) -> PipeRef
+    {
+        return PipeRef {
+            pipe : self.pipe
+        };
+    }
+
+    /**
+     * Check if the pipe contains no more data. 
+     *
+     * This is meaningful only when we are currently executing some execution task with this servlet. 
+     * Which means it only can be called from either `exec` and `async_init`, `async_cleanup` stage
+     * of a servlet. Otherwise it will returns a failure.
+     *
+     * The EOF function in Plumber defines a little bit different from normal EOF. It indicates if
+     * it's possible to have further data.
+     *
+     * If this function returns `true`, it's possible we have more data in the furture, but it's **not** 
+     * means we current have data to read. It's also possible that there's no more data but the
+     * framework is not able to realize that currently. 
+     *
+     * If this function returns `false`, it indicates there are definitely no more data can be read
+     * from this port. 
+     *
+     * Returns either None on error case or the check result
+     **/
+    pub fn eof(&mut self) -> Option<bool>
+    {
+        plumber_api_call!{
+            let result = eof(self.pipe) in {
+                if result as i32 != -1
+                {
+                    return Some(result > 0);
+                }
+                return None;
+            }
+        }
+
+        return None;
+    }
+}
+
+impl <ST> Pipe<Output, ST> {
+
+    /**
+     * Define a shadow output pipe port, which is a copy of the input pipe port. This is also
+     * called a fork, all the data of the source input is forwarded to the shadow output as well,
+     * so that the dataflow is split into multiple ways.
+     *
+     * Like `define`, this function can only be called during the initialization stage.
+     *
+     * * `name`: The name of the port
+     * * `source`: The input pipe port that this pipe port is copying
+     *
+     * Returns either `None` on creating failure or the newly created pipe object
+     **/
+    pub fn define_shadow<SST>(name:&str, source:&Pipe<Input, SST>) -> Option<Pipe<Output, ST>>
+    {
+        if let Some(flags) = PipeFlags::shadow_of(source.as_descriptor())
+        {
+            return Self::define(name, flags, None);
+        }
+        return None;
+    }
+
+    /**
+     * Stop forwarding the data of the source input to this shadow output for the current
+     * activation. This only works for a pipe port created by `define_shadow`.
+     *
+     * Returns the operation result, `None` indicates failure or the pipe isn't a shadow output
+     **/
+    pub fn disable(&mut self) -> Option<()>
+    {
+        if !self.check_flag(PIPE_SHADOW)?
+        {
+            return None;
+        }
+        return self.set_flags(PIPE_DISABLED);
+    }
+
+    /**
+     * Forward the data of the source input to this shadow output for the current activation,
+     * which undoes `disable`. This only works for a pipe port created by `define_shadow`.
+     *
+     * Returns the operation result, `None` indicates failure or the pipe isn't a shadow output
+     **/
+    pub fn enable(&mut self) -> Option<()>
+    {
+        if !self.check_flag(PIPE_SHADOW)?
+        {
+            return None;
+        }
+        return self.clear_flags(PIPE_DISABLED);
+    }
+
+    /**
+     * Check if this shadow output is disabled for the current activation
+     *
+     * Returns either None on error or if the pipe isn't a shadow output, or the check result
+     **/
+    pub fn is_disabled(&mut self) -> Option<bool>
+    {
+        if !self.check_flag(PIPE_SHADOW)?
+        {
+            return None;
+        }
+        return self.check_flag(PIPE_DISABLED);
+    }
+
+    /**
+     * Write a request local scope (RLS) token to the pipe.
+     *
+     * Instead of copying the data into the pipe, this writes the RLS object as a reference, and
+     * the framework serializes it as a byte stream when the data reaches the other end, which
+     * avoids the userspace copy.
+     *
+     * * `token`: The RLS token to write
+     *
+     * Returns the operation result, `None` indicates failure
+     **/
+    pub fn write_scope_token(&mut self, token:ScopeToken) -> Option<()>
+    {
+        plumber_api_call!{
+            let result = write_scope_token(self.pipe, token, ::std::ptr::null()) in {
+                if result != -1
+                {
+                    return Some(());
+                }
+            }
+        }
+        return None;
+    }
+
+}
+
+impl <ST> Read for Pipe<Input, ST> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize>
     {
         plumber_api_call!{
@@ -396,7 +976,7 @@ impl <ST> Read for Pipe<ST> {
     }
 }
 
-impl <ST> Write for Pipe<ST> {
+impl <ST> Write for Pipe<Output, ST> {
     fn write(&mut self, buf:&[u8]) -> Result<usize>
     {
         plumber_api_call!{
@@ -416,3 +996,187 @@ impl <ST> Write for Pipe<ST> {
         return Ok(());
     }
 }
+
+/**
+ * The error type for defining a set of pipes, which carries the name of the pipe that failed
+ **/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipeDefineError {
+    /// The name of the pipe that can not be defined
+    pub name : String
+}
+
+impl Display for PipeDefineError {
+    fn fmt(&self, f:&mut Formatter) -> std::fmt::Result
+    {
+        return write!(f, "cannot define pipe {}", self.name);
+    }
+}
+
+impl std::error::Error for PipeDefineError {}
+
+/**
+ * The trait for a struct that holds all the pipes of a servlet, which is usually implemented by
+ * the macro `pipe_set!`
+ **/
+pub trait PipeSet : Sized {
+    /**
+     * Define all the pipes in the set. This should be called during the servlet initialization
+     * stage, typically in `Bootstrap::get`.
+     *
+     * Returns the pipe set or the error describes which pipe can not be defined
+     **/
+    fn define_all() -> std::result::Result<Self, PipeDefineError>;
+
+    /**
+     * Get the map from the pipe name to the pipe descriptor, which is used to initialize the
+     * protocol model. See `init_protocol!` for details.
+     *
+     * Returns the newly created map
+     **/
+    fn pipe_map(&self) -> HashMap<String, PipeDescriptor>;
+
+    /**
+     * The schema of all the pipes in the set, which is exported in the servlet metadata
+     **/
+    const SCHEMA : &'static [PipeSchema];
+}
+
+/**
+ * Declare a struct that holds all the pipes of the servlet, and define all of them at once.
+ *
+ * This takes the place of a `#[derive(Pipes)]` on the servlet struct. A derive would need a
+ * procedural macro crate, which this crate doesn't have, so the pipe set is declared with the
+ * declarative macro instead, and `PipeSet::define_all` is the generated `define_all()`.
+ *
+ * The syntax of each pipe is:
+ *
+ * ```
+ *  "pipe_name" [flags] : "type_expr" => field_name : pipe_type;
+ * ```
+ *
+ * The flags are the pipe flag constants, such as `PIPE_OUTPUT`, combined with `|`. The direction
+ * of the pipe is taken from the pipe type, and declaring `PIPE_OUTPUT` for an input pipe is a
+ * compile error. `PIPE_INPUT` doesn't have any bit set, so it's only a marker and doesn't change
+ * the direction. The type expression is optional, an untyped pipe is declared without it. For
+ * example:
+ *
+ * ```rust
+ *     pipe_set! {
+ *         pipes ServletPipes {
+ *             "input"  [PIPE_INPUT]  : "graphics/Point2D" => input  : Pipe<Input>;
+ *             "output" [PIPE_OUTPUT] : "float"            => output : Pipe<Output>;
+ *             "log"    [PIPE_OUTPUT]                      => log    : Pipe<Output>;
+ *         }
+ *     }
+ *
+ *     // In Bootstrap::get
+ *     match ServletPipes::define_all() {
+ *         Ok(pipes) => return Self::make_sync(Servlet{ pipes : pipes }),
+ *         Err(err)  => { plumber_log!(E "{}", err); return Self::fail(); }
+ *     }
+ *
+ *     // In the init function
+ *     init_protocol!(model from self.pipes);
+ * ```
+ *
+ * The protocol model is initialized with the pipe names, so the pipe name used in `protodef!`
+ * should be the same as the name of the pipe. The pipe set can also be used to export the pipe
+ * schema, see `plumber_rs::metadata` for details.
+ **/
+#[macro_export]
+macro_rules! pipe_set {
+    (@type) => {
+        None
+    };
+    (@type $type_expr:literal) => {
+        Some($type_expr)
+    };
+    ($(pipes $name:ident { $($pipe_name:literal [$($flag:path)|+] $(: $type_expr:literal)? => $field:ident : $type:ty;)* })*) => {
+        $(
+        pub struct $name {
+            $(pub $field : $type,)*
+        }
+
+        impl $crate::pipe::PipeSet for $name {
+            const SCHEMA : &'static [$crate::metadata::PipeSchema] = &[
+                $($crate::metadata::PipeSchema {
+                    name      : $pipe_name,
+                    flags     : <$type as $crate::pipe::PipeKind>::DIRECTION_FLAG$(.union($crate::pipe::declared_flag::<$type>($flag)))+,
+                    type_expr : $crate::pipe_set!(@type $($type_expr)?)
+                },)*
+            ];
+
+            fn define_all() -> Result<$name, $crate::pipe::PipeDefineError>
+            {
+                use $crate::pipe::PipeDefineError;
+
+                return Ok($name {
+                    $($field : match <$type>::define($pipe_name, $crate::pipe::PipeFlags::empty()$(.union($flag))+, $crate::pipe_set!(@type $($type_expr)?)) {
+                        Some(pipe) => pipe,
+                        None       => return Err(PipeDefineError{ name : $pipe_name.to_string() })
+                    },)*
+                });
+            }
+
+            fn pipe_map(&self) -> ::std::collections::HashMap<String, $crate::pipe::PipeDescriptor>
+            {
+                let mut pipe_map = ::std::collections::HashMap::new();
+                $(pipe_map.insert($pipe_name.to_string(), self.$field.as_descriptor());)*
+                return pipe_map;
+            }
+        }
+
+        // Make sure the declared flags are checked even if the schema isn't exported
+        const _ : &'static [$crate::metadata::PipeSchema] = <$name as $crate::pipe::PipeSet>::SCHEMA;
+        )*
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_combinations_are_rejected()
+    {
+        assert!((PIPE_OUTPUT | PIPE_PERSIST | PIPE_ASYNC).is_valid());
+        assert!(PipeFlags::shadow_of(3).unwrap().union(PIPE_DISABLED).is_valid());
+
+        assert!(!PipeFlags::from_bits_retain(0x200000).is_valid());
+        assert!(!PipeFlags::from_bits_retain(PIPE_OUTPUT.bits() | 3).is_valid());
+        assert!(!PIPE_ASYNC.is_valid());
+        assert!(!PIPE_SHADOW.is_valid());
+        assert!(!(PIPE_OUTPUT | PIPE_DISABLED).is_valid());
+    }
+
+    #[test]
+    fn from_bits_checks_the_flags()
+    {
+        assert_eq!(PipeFlags::from_bits(0x30000), Some(PIPE_OUTPUT | PIPE_PERSIST));
+        assert_eq!(PipeFlags::from_bits(0x40000), None);
+        assert_eq!(PipeFlags::from_bits(0x5000000), Some(PipeFlags::module(5)));
+        assert_eq!(PipeFlags::from_bits_retain(0x40000).bits(), 0x40000);
+    }
+
+    #[test]
+    fn shadow_flags_carry_the_source_pipe()
+    {
+        let flags = PipeFlags::shadow_of(42).unwrap();
+
+        assert!(flags.contains(PIPE_OUTPUT | PIPE_SHADOW));
+        assert_eq!(flags.target(), Some(42));
+        assert_eq!(PipeFlags::shadow_of(0x10000), None);
+        assert_eq!((PIPE_OUTPUT | PIPE_PERSIST).target(), None);
+    }
+
+    #[test]
+    fn debug_shows_the_flag_names()
+    {
+        assert_eq!(format!("{:?}", PIPE_INPUT), "PipeFlags(INPUT)");
+        assert_eq!(format!("{:?}", PIPE_OUTPUT | PIPE_PERSIST | PIPE_ASYNC), "PipeFlags(OUTPUT | PERSIST | ASYNC)");
+        assert_eq!(format!("{:?}", PipeFlags::shadow_of(7).unwrap() | PIPE_DISABLED), "PipeFlags(OUTPUT | SHADOW | DISABLED, target: 7)");
+        assert_eq!(format!("{:?}", PIPE_OUTPUT | PipeFlags::module(0x12) | PipeFlags::from_bits_retain(0x800000)),
+                   "PipeFlags(OUTPUT | 0x800000, module: 0x12)");
+    }
+}
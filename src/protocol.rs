@@ -13,18 +13,103 @@ use crate::pstd::{
     pstd_type_field_t, 
     pstd_type_model_t, 
     pstd_type_instance_t, 
-    pstd_type_model_get_accessor,
     pstd_type_model_get_field_info,
     pstd_type_model_on_pipe_type_checked,
+    pstd_type_model_copy_pipe_data,
+    pstd_type_model_batch_init,
+    pstd_type_model_init_param_t,
+    pstd_type_model_init_param_t__bindgen_ty_1,
     pstd_type_instance_read,
-    pstd_type_instance_write
+    pstd_type_instance_write,
+    pstd_type_instance_field_size
 };
 
 use crate::plumber_api_call::get_cstr;
+use crate::log::log_write;
 
 use std::marker::PhantomData;
 use std::collections::HashMap;
 use std::rc::Rc;
+use std::fmt::{Display, Formatter};
+
+/**
+ * The error type for the typed field access.
+ *
+ * This is returned by the error-returning variants of the field operations, such as `try_get`
+ * and `try_set`. The execution function can log it and fail with `map_err` and the `?` operator.
+ **/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldError {
+    /// The field hasn't been assigned to the type model, typically `init_protocol!` is missing
+    Unassigned,
+    /// The type instance is unable to read the field
+    ReadFailure,
+    /// The type instance is unable to write the field
+    WriteFailure,
+    /// The size of the data doesn't match the size of the field
+    SizeMismatch
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f:&mut Formatter) -> std::fmt::Result
+    {
+        let message = match self {
+            FieldError::Unassigned   => "the field is not assigned to the type model",
+            FieldError::ReadFailure  => "cannot read the field from the type instance",
+            FieldError::WriteFailure => "cannot write the field to the type instance",
+            FieldError::SizeMismatch => "the data size doesn't match the field size"
+        };
+        return write!(f, "{}", message);
+    }
+}
+
+impl std::error::Error for FieldError {}
+
+/**
+ * The error type for the protocol model initialization.
+ *
+ * It carries the name of the pipe and field that causes the failure, so that the servlet is able
+ * to report exactly what is wrong with the protocol definition.
+ **/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolInitError {
+    /// The pipe used by the protocol isn't assigned with `init_protocol!`
+    MissingPipe(String),
+    /// The field doesn't exist in the type of the pipe, or the type information is unavailable
+    InvalidField {
+        /// The name of the pipe
+        pipe  : String,
+        /// The field expression
+        field : String
+    },
+    /// The type model is unable to copy the pipe data
+    CopyFailure {
+        /// The pipe we copy the data from
+        from : String,
+        /// The pipe we copy the data to
+        to   : String
+    },
+    /// The type model batch initialization failed, but the failed field is unknown
+    BatchFailure
+}
+
+impl Display for ProtocolInitError {
+    fn fmt(&self, f:&mut Formatter) -> std::fmt::Result
+    {
+        return match self {
+            ProtocolInitError::MissingPipe(pipe) => 
+                write!(f, "pipe {} is not assigned to the protocol", pipe),
+            ProtocolInitError::InvalidField{pipe, field} => 
+                write!(f, "cannot initialize field {}.{}", pipe, field),
+            ProtocolInitError::CopyFailure{from, to} => 
+                write!(f, "cannot copy the pipe data from {} to {}", from, to),
+            ProtocolInitError::BatchFailure => 
+                write!(f, "the type model batch initialization failed")
+        };
+    }
+}
+
+impl std::error::Error for ProtocolInitError {}
 
 /**
  * Type type instance object. For each time the Plumber framework activate the execution of the
@@ -100,6 +185,28 @@ impl TypeInstanceObject {
 
         return result != -1;
     }
+
+    /**
+     * Get the actual size of the field in current type instance.
+     *
+     * This is useful when the size of the field can only be determined at runtime, for example, an
+     * array field.
+     *
+     * * `acc`: The accessor of the field
+     *
+     * Returns the size of the field in bytes or None on error
+     **/
+    fn field_size(&mut self, acc:pstd_type_accessor_t) -> Option<usize>
+    {
+        let result = unsafe{ pstd_type_instance_field_size(self.object, acc) };
+
+        if result as isize == -1
+        {
+            return None;
+        }
+
+        return Some(result);
+    }
 }
 
 /**
@@ -137,17 +244,72 @@ pub struct TypeModelObject {
 }
 
 /**
- * The additonal data used when we want to check the type shape of the primitive
+ * The parameter used to initialize a field with `TypeModelObject::batch_init`. 
+ *
+ * This is created by `ProtocolField::init_param` and it's only valid until the field is moved.
+ **/
+pub struct FieldInitParam<'a> {
+    /// The name of the pipe
+    pipe_name : &'a str,
+    /// The pipe descriptor
+    pipe      : PipeDescriptor,
+    /// The field expression
+    path      : &'a str,
+    /// The accessor of the field, which is None until the initialization succeeds
+    accessor  : *mut Option<pstd_type_accessor_t>,
+    /// The buffer where the type shape should be written to, it must live until the type checking is done
+    shape     : *mut PrimitiveTypeShape,
+    /// The function used to validate the type shape
+    validate  : Option<fn(&PrimitiveTypeShape) -> bool>
+}
+
+/**
+ * The accessor value libpstd uses to indicate an error
+ **/
+const ERROR_ACCESSOR : pstd_type_accessor_t = !0;
+
+/**
+ * A single type shape check registered by the batch initialization
  **/
-struct TypeShapeChecker<'a , T : PrimitiveTypeTag<T> + Default> {
+struct TypeShapeCheck {
     /// The shape buffer that will be written when the type inference is done
-    shape   : &'a PrimitiveTypeShape,
-    /// Keep the type information
-    phantom : PhantomData<T>
+    shape    : *const PrimitiveTypeShape,
+    /// The function used to validate the type shape
+    validate : fn(&PrimitiveTypeShape) -> bool,
+    /// The field expression, used for error reporting
+    path     : String
 }
 
-impl <'a, T:PrimitiveTypeTag<T> + Default> TypeShapeChecker<'a,T> {
-    fn do_check(&self) -> bool { return T::validate_type_shape(self.shape); }
+/**
+ * All the type shape checks for a single pipe
+ **/
+struct TypeShapeCheckList {
+    /// The name of the pipe
+    pipe_name : String,
+    /// The checks for this pipe, the list is freed by the callback once the type checking is done
+    checks    : Vec<TypeShapeCheck>
+}
+
+extern "C" fn _validate_type_shape_list(_pipe: crate::plumber_api::runtime_api_pipe_t, 
+                                        data : *mut std::os::raw::c_void) -> i32
+{
+    if !data.is_null()
+    {
+        let list = unsafe { Box::from_raw(data as *mut TypeShapeCheckList) };
+
+        for check in list.checks.iter()
+        {
+            if !(check.validate)(unsafe { &*check.shape })
+            {
+                log_write(1, file!(), line!() as i32, 
+                          &format!("Type shape mismatch: field {}.{} doesn't match the Rust type", list.pipe_name, check.path));
+                return -1;
+            }
+        }
+
+        return 0;
+    }
+    return -1;
 }
 
 impl TypeModelObject {
@@ -175,174 +337,854 @@ impl TypeModelObject {
 
 
     /**
-     * Add a check of type shape for the accessor
+     * Query the type shape of the field from the protocol database
      **/
-    fn _add_type_shape_check<T>(&self, 
-                                pipe:PipeDescriptor,
-                                path:*const crate::std::os::raw::c_char,
-                                primitive:&mut Primitive<T>) -> bool
-        where T : PrimitiveTypeTag<T> + Default
+    fn _get_field_info(&self, 
+                       pipe:PipeDescriptor,
+                       path:*const crate::std::os::raw::c_char,
+                       shape:&mut PrimitiveTypeShape) -> bool
     {
-        if -1 == unsafe { 
+        return -1 != unsafe { 
             pstd_type_model_get_field_info(self.object, 
                                            pipe, 
                                            path, 
-                                           (&mut primitive.shape) as *mut PrimitiveTypeShape) 
+                                           shape as *mut PrimitiveTypeShape) 
+        };
+    }
+
+    /**
+     * Assign a primitive data object to the type model. This will cause the Plumber framework
+     * check the protocol database and keep the type information in the primitive object for
+     * further protocol parsing
+     *
+     * This is a single field `batch_init`.
+     *
+     * * `pipe`: The pipe we want to access
+     * * `pipe_name`: The name of the pipe, used for error reporting
+     * * `path`: The path to the pipe 
+     * * `primitive`: The primitive object
+     * * `validate_type` If we want to validate the type shape
+     *
+     * Returns if the operation has sucessfully completed
+     **/
+    pub fn assign_primitive<'a, 'b, T>(&self, 
+                                          pipe:PipeDescriptor, 
+                                          pipe_name:&'a str,
+                                          path:&'a str, 
+                                          primitive:&'b mut Primitive<T>, 
+                                          validate_type:bool) -> bool 
+        where T : PrimitiveTypeTag<T> + Default
+    {
+        if let None = primitive.accessor 
+        {
+            let param = primitive.init_param(pipe_name, pipe, path, validate_type);
+            return self.batch_init(&[param], file!(), line!()).is_ok();
+        }
+
+        return false;
+    }
+
+    /**
+     * Reset the accessors of the fields after a failed batch initialization, so that the fields
+     * are reported as unassigned
+     *
+     * * `params`: The fields to reset
+     **/
+    fn reset_accessors(params:&[FieldInitParam])
+    {
+        for param in params.iter()
+        {
+            unsafe { *param.accessor = None };
+        }
+    }
+
+    /**
+     * Initialize a group of fields with a single batch initialization of libpstd.
+     *
+     * This is the only way to initialize the fields of a protocol model, and it's used by
+     * `protodef!`. The type shape of every field is queried first, which reports the field that
+     * doesn't exist, and then all the accessors are required with `pstd_type_model_batch_init`.
+     * The type shape validations of each pipe are registered as a single type checked callback
+     * and the validation data is freed once the type checking is done.
+     *
+     * If the batch initialization itself fails, libpstd doesn't tell which field causes the
+     * failure, so `ProtocolInitError::BatchFailure` is returned. The fields are left unassigned
+     * whenever an error is returned.
+     *
+     * * `params`: The list of the fields to initialize, see `ProtocolField::init_param`
+     * * `file`: The source file defines the fields
+     * * `line`: The line number in the source file
+     *
+     * Returns the operation result, or the error which carries the failed field if it's known
+     **/
+    pub fn batch_init(&self, params:&[FieldInitParam], file:&str, line:u32) -> Result<(), ProtocolInitError>
+    {
+        let result = self._batch_init(params, file, line);
+
+        if result.is_err()
+        {
+            TypeModelObject::reset_accessors(params);
+        }
+
+        return result;
+    }
+
+    /**
+     * The actual batch initialization, see `batch_init`
+     **/
+    fn _batch_init(&self, params:&[FieldInitParam], file:&str, line:u32) -> Result<(), ProtocolInitError>
+    {
+        let invalid_field = |param:&FieldInitParam| {
+            return ProtocolInitError::InvalidField {
+                pipe  : param.pipe_name.to_string(),
+                field : param.path.to_string()
+            };
+        };
+
+        let mut cstrs = Vec::new();
+
+        for param in params.iter()
+        {
+            let (c_pipe, _pipe) = get_cstr(Some(param.pipe_name));
+            let (c_path, _path) = get_cstr(Some(param.path));
+
+            if c_pipe.is_null() || c_path.is_null()
+            {
+                return Err(invalid_field(param));
+            }
+
+            // Query the field info for every field, so that an invalid field is reported before
+            // the batch initialization, which doesn't tell which field has failed. libpstd keeps
+            // the shape buffer until the type checking is done, so it can't be a temporary one.
+            let shape = match unsafe { param.shape.as_mut() } {
+                Some(shape) => shape,
+                None        => return Err(invalid_field(param))
+            };
+
+            if !self._get_field_info(param.pipe, c_path, shape)
+            {
+                return Err(invalid_field(param));
+            }
+
+            cstrs.push((c_pipe, c_path, _pipe, _path));
+        }
+
+        let mut accessor_bufs = Vec::new();
+
+        for param in params.iter()
+        {
+            accessor_bufs.push(unsafe { (*param.accessor).get_or_insert(ERROR_ACCESSOR) } as *mut pstd_type_accessor_t);
+
+        }
+
+        let (c_file, _file) = get_cstr(Some(file));
+
+        let pstd_params:Vec<pstd_type_model_init_param_t> = params.iter().zip(cstrs.iter()).zip(accessor_bufs.iter()).map(|((param, cstr), accessor_buf)| {
+            return pstd_type_model_init_param_t {
+                _bitfield_1      : pstd_type_model_init_param_t::new_bitfield_1(0),
+                __bindgen_anon_1 : pstd_type_model_init_param_t__bindgen_ty_1 {
+                    accessor_buf : *accessor_buf
+                },
+                field_expr       : cstr.1,
+                pipe             : param.pipe,
+                filename         : c_file,
+                line             : line,
+                pipe_name        : cstr.0
+            };
+        }).collect();
+
+        if pstd_params.len() > 0 && 
+           unsafe { pstd_type_model_batch_init(pstd_params.as_ptr(), pstd_params.len(), self.object) }.is_null()
+        {
+            return Err(ProtocolInitError::BatchFailure);
+        }
+
+        let mut pipes:Vec<PipeDescriptor> = Vec::new();
+
+        for param in params.iter()
+        {
+            if param.validate.is_some() && !pipes.contains(&param.pipe)
+            {
+                pipes.push(param.pipe);
+            }
+        }
+
+        for pipe in pipes
+        {
+            let mut check_list = Box::new(TypeShapeCheckList {
+                pipe_name : String::new(),
+                checks    : Vec::new()
+            });
+
+            for param in params.iter().filter(|param| param.pipe == pipe)
+            {
+                if let Some(validate) = param.validate
+                {
+                    check_list.pipe_name = param.pipe_name.to_string();
+                    check_list.checks.push(TypeShapeCheck {
+                        shape    : param.shape,
+                        validate : validate,
+                        path     : param.path.to_string()
+                    });
+                }
+            }
+
+            let pipe_name = check_list.pipe_name.clone();
+            let field = check_list.checks[0].path.clone();
+
+            let check_list_ptr = Box::into_raw(check_list);
+
+            if -1 == unsafe { pstd_type_model_on_pipe_type_checked(self.object, 
+                                                                   pipe, 
+                                                                   Some(_validate_type_shape_list), 
+                                                                   check_list_ptr as *mut std::os::raw::c_void) }
+            {
+                drop(unsafe { Box::from_raw(check_list_ptr) });
+                return Err(ProtocolInitError::InvalidField {
+                    pipe  : pipe_name,
+                    field : field
+                });
+            }
+        }
+
+        return Ok(());
+    }
+
+    /**
+     * Make the `to` pipe carry a copy of the typed data from the `from` pipe each time the servlet
+     * gets executed.
+     *
+     * This is useful when the servlet only modifies a few fields of the input and forwards
+     * everything else to the output unchanged. The untouched fields are copied by libpstd, so the
+     * servlet only needs to map the fields it actually changes. The type of `from` pipe must be a
+     * sub-type of the type of `to` pipe.
+     *
+     * This function is designed to be called from the servlet's init function.
+     *
+     * * `from`: The pipe we want to copy the data from
+     * * `to`: The pipe we want to copy the data to
+     *
+     * Returns if the operation has successfully completed
+     **/
+    pub fn copy_pipe_data(&self, from:PipeDescriptor, to:PipeDescriptor) -> bool
+    {
+        return -1 != unsafe { pstd_type_model_copy_pipe_data(self.object, from, to) };
+    }
+}
+
+/**
+ * The object used to represent a pritmive type in the language-neutral protocol database
+ **/
+pub struct Primitive<ActualType : PrimitiveTypeTag<ActualType> + Default> {
+    /// The type accessor object
+    accessor : Option<pstd_type_accessor_t>,
+    /// The shape of this primmitive
+    shape    : PrimitiveTypeShape,
+    /// The type holder
+    _phantom : PhantomData<ActualType>
+}
+
+impl <T : PrimitiveTypeTag<T> + Default> Primitive<T> {
+    /**
+     * Create a new type primitive
+     **/
+    pub fn new() -> Primitive<T>
+    {
+        return Primitive {
+            accessor : None,
+            shape    : Default::default(),
+            _phantom : PhantomData
+        };
+    }
+
+    /**
+     * Get a primitive value from the primitive descriptor. 
+     *
+     * This function will be valid only when it's called from execution function and there's
+     * type instance object has been created. Otherwise it will returns a failure
+     *
+     * * `type_inst`: Type instance object where we read the primitive from
+     * 
+     * Return the read result, None indicates we are unable to read the data
+     **/
+    pub fn get(&self, type_inst:&mut TypeInstanceObject) -> Option<T>
+    {
+        return self.try_get(type_inst).ok();
+    }
+
+    /**
+     * Get a primitive value from the primitive descriptor, and report the reason of failure.
+     *
+     * See `get` for the details.
+     *
+     * * `type_inst`: Type instance object where we read the primitive from
+     *
+     * Return the read result or the error
+     **/
+    pub fn try_get(&self, type_inst:&mut TypeInstanceObject) -> Result<T, FieldError>
+    {
+        if let Some(ref acc_ref) = self.accessor
+        {
+            let mut buf:T = Default::default();
+            let buf_ptr = &mut buf as *mut T;
+            let acc = acc_ref.clone();
+
+            if type_inst.read(acc, buf_ptr as *mut std::os::raw::c_void, std::mem::size_of::<T>())
+            {
+                return Ok(buf);
+            }
+
+            return Err(FieldError::ReadFailure);
+        }
+
+        return Err(FieldError::Unassigned);
+    }
+
+    /**
+     * Write a primitive to the primitive descriptor within current task context
+     *
+     * This function will be valid only when it's called from the execution function, because it
+     * requires the task context.
+     *
+     * * `type_inst`: The type instance object where we want to write to
+     *
+     * Return the operation result, if the operation has successuflly  done.
+     **/
+    pub fn set(&self, type_inst:&mut TypeInstanceObject, val:T) -> bool
+    {
+        return self.try_set(type_inst, val).is_ok();
+    }
+
+    /**
+     * Write a primitive to the primitive descriptor, and report the reason of failure.
+     *
+     * See `set` for the details.
+     *
+     * * `type_inst`: The type instance object where we want to write to
+     * * `val`: The value to write
+     *
+     * Return the operation result or the error
+     **/
+    pub fn try_set(&self, type_inst:&mut TypeInstanceObject, val:T) -> Result<(), FieldError>
+    {
+        return self.write_ref(type_inst, &val);
+    }
+
+    /**
+     * Write the primitive from a reference to the value
+     **/
+    fn write_ref(&self, type_inst:&mut TypeInstanceObject, val:&T) -> Result<(), FieldError>
+    {
+        if let Some(ref acc_ref) = self.accessor
+        {
+            let acc = acc_ref.clone();
+            let val_ptr = val as *const T;
+
+            if type_inst.write(acc, val_ptr as *mut std::os::raw::c_void, std::mem::size_of::<T>())
+            {
+                return Ok(());
+            }
+
+            return Err(FieldError::WriteFailure);
+        }
+        return Err(FieldError::Unassigned);
+    }
+
+    /**
+     * Check if the primitive is present in the type instance.
+     *
+     * * `type_inst`: The type instance object to check
+     *
+     * Returns if the field has been assigned and contains data in the type instance
+     **/
+    pub fn is_present(&self, type_inst:&mut TypeInstanceObject) -> bool
+    {
+        if let Some(acc) = self.accessor
+        {
+            if acc != ERROR_ACCESSOR
+            {
+                return type_inst.field_size(acc).map_or(false, |size| size > 0);
+            }
         }
+        return false;
+    }
+}
+
+/**
+ * The object used to represent an array of primitives in the language-neutral protocol database.
+ *
+ * Unlike `Primitive`, the size of an array field isn't known from the Rust type. The size of the
+ * field is read from the type instance at runtime, so that both fixed-length and variable-width
+ * fields can be read.
+ **/
+pub struct PrimitiveArray<ElementType : PrimitiveTypeTag<ElementType> + Default + Copy> {
+    /// The type accessor object
+    accessor : Option<pstd_type_accessor_t>,
+    /// The shape of the entire array field
+    shape    : PrimitiveTypeShape,
+    /// The type holder
+    _phantom : PhantomData<ElementType>
+}
+
+impl <T : PrimitiveTypeTag<T> + Default + Copy> PrimitiveArray<T> {
+    /**
+     * Create a new primitive array
+     **/
+    pub fn new() -> PrimitiveArray<T>
+    {
+        return PrimitiveArray {
+            accessor : None,
+            shape    : Default::default(),
+            _phantom : PhantomData
+        };
+    }
+
+    /**
+     * Validate the type shape of the array field.
+     *
+     * The array field shape describes the entire memory region of the array, so we check the
+     * element type with the element size and make sure the region contains whole elements.
+     **/
+    fn validate_type_shape(shape : &PrimitiveTypeShape) -> bool
+    {
+        let elem_size = std::mem::size_of::<T>() as u32;
+
+        if shape.size % elem_size != 0
         {
             return false;
         }
 
-        let check_shape = Box::new(TypeShapeChecker::<T>{
-            shape : &primitive.shape,
-            phantom: PhantomData
-        });
+        let mut elem_shape = *shape;
+        elem_shape.size = elem_size;
+
+        return T::validate_type_shape(&elem_shape);
+    }
+
+    /**
+     * Get the number of elements of the array field declared in the protocol database.
+     *
+     * For a variable-width field, this is the size known at the init time, the actual size of
+     * the field in a specific task is returned by `get`.
+     *
+     * Returns the number of elements
+     **/
+    pub fn capacity(&self) -> usize
+    {
+        return self.shape.size as usize / std::mem::size_of::<T>();
+    }
+
+    /**
+     * Get the array from the array descriptor. 
+     *
+     * The size of the array is determined by the type instance with
+     * `pstd_type_instance_field_size`, so it can be used for the variable-width fields as well.
+     *
+     * This function will be valid only when it's called from execution function and there's
+     * type instance object has been created. Otherwise it will returns a failure
+     *
+     * * `type_inst`: Type instance object where we read the array from
+     * 
+     * Return the read result, None indicates we are unable to read the data
+     **/
+    pub fn get(&self, type_inst:&mut TypeInstanceObject) -> Option<Vec<T>>
+    {
+        return self.try_get(type_inst).ok();
+    }
+
+    /**
+     * Get the array from the array descriptor, and report the reason of failure.
+     *
+     * See `get` for the details.
+     *
+     * * `type_inst`: Type instance object where we read the array from
+     *
+     * Return the read result or the error
+     **/
+    pub fn try_get(&self, type_inst:&mut TypeInstanceObject) -> Result<Vec<T>, FieldError>
+    {
+        if let Some(ref acc_ref) = self.accessor
+        {
+            let acc = acc_ref.clone();
+
+            if let Some(size) = type_inst.field_size(acc)
+            {
+                let elem_size = std::mem::size_of::<T>();
+
+                if size % elem_size != 0
+                {
+                    return Err(FieldError::SizeMismatch);
+                }
+
+                let mut buf:Vec<T> = vec![Default::default(); size / elem_size];
+
+                if size == 0 || type_inst.read(acc, buf.as_mut_ptr() as *mut std::os::raw::c_void, size)
+                {
+                    return Ok(buf);
+                }
+            }
+
+            return Err(FieldError::ReadFailure);
+        }
+
+        return Err(FieldError::Unassigned);
+    }
+
+    /**
+     * Write an array to the array descriptor within current task context.
+     *
+     * The array field has a fixed length defined by the protocol, so the length of the slice must
+     * be exactly the capacity of the field.
+     *
+     * This function will be valid only when it's called from the execution function, because it
+     * requires the task context.
+     *
+     * * `type_inst`: The type instance object where we want to write to
+     * * `val`: The array we want to write
+     *
+     * Return the operation result, if the operation has successuflly  done.
+     **/
+    pub fn set(&self, type_inst:&mut TypeInstanceObject, val:&[T]) -> bool
+    {
+        return self.try_set(type_inst, val).is_ok();
+    }
+
+    /**
+     * Write an array to the array descriptor, and report the reason of failure.
+     *
+     * See `set` for the details.
+     *
+     * * `type_inst`: The type instance object where we want to write to
+     * * `val`: The array we want to write
+     *
+     * Return the operation result or the error
+     **/
+    pub fn try_set(&self, type_inst:&mut TypeInstanceObject, val:&[T]) -> Result<(), FieldError>
+    {
+        if let Some(ref acc_ref) = self.accessor
+        {
+            if val.len() != self.capacity()
+            {
+                return Err(FieldError::SizeMismatch);
+            }
+
+            let acc = acc_ref.clone();
+            let size = val.len() * std::mem::size_of::<T>();
+
+            if type_inst.write(acc, val.as_ptr() as *const std::os::raw::c_void, size)
+            {
+                return Ok(());
+            }
+
+            return Err(FieldError::WriteFailure);
+        }
+        return Err(FieldError::Unassigned);
+    }
 
-        extern "C" fn _validate_primitive_type_shape<T>(_pipe: crate::plumber_api::runtime_api_pipe_t, 
-                                                        data : *mut std::os::raw::c_void) -> i32
-            where T : PrimitiveTypeTag<T>+Default
+    /**
+     * Check if the array is present in the type instance.
+     *
+     * * `type_inst`: The type instance object to check
+     *
+     * Returns if the field has been assigned and contains data in the type instance
+     **/
+    pub fn is_present(&self, type_inst:&mut TypeInstanceObject) -> bool
+    {
+        if let Some(acc) = self.accessor
         {
-            let check_shape = unsafe{ Box::<TypeShapeChecker<T>>::from_raw(data as *mut TypeShapeChecker<T>) };
-            if check_shape.do_check()
+            if acc != ERROR_ACCESSOR
             {
-                return 0;
+                return type_inst.field_size(acc).map_or(false, |size| size > 0);
             }
-            return -1;
         }
+        return false;
+    }
+}
+
+/**
+ * The trait for the field descriptors that can be used in a protocol model, for example
+ * `Primitive` and `PrimitiveArray`.
+ *
+ * This trait is used by the `protodef!` macro to assign the fields without knowing if the field
+ * is a primitive or an array.
+ **/
+pub trait ProtocolField {
+    /**
+     * The Rust type of the value carried by this field
+     **/
+    type Value;
+
+    /**
+     * Create a new unassigned field descriptor
+     **/
+    fn new_field() -> Self;
 
-        let check_shape_ref = Box::leak(check_shape) as *mut TypeShapeChecker<T>;
+    /**
+     * Read the value of the field from the type instance
+     *
+     * * `type_inst`: The type instance object to read
+     *
+     * Returns the value or the error
+     **/
+    fn read_value(&self, type_inst:&mut TypeInstanceObject) -> Result<Self::Value, FieldError>;
+
+    /**
+     * Write the value of the field to the type instance
+     *
+     * * `type_inst`: The type instance object to write
+     * * `val`: The value to write
+     *
+     * Returns the operation result or the error
+     **/
+    fn write_value(&self, type_inst:&mut TypeInstanceObject, val:&Self::Value) -> Result<(), FieldError>;
+
+    /**
+     * Check if the field is present in the type instance
+     *
+     * * `type_inst`: The type instance object to check
+     *
+     * Returns the check result
+     **/
+    fn is_present(&self, type_inst:&mut TypeInstanceObject) -> bool;
+
+    /**
+     * Create the parameter used to initialize this field with `TypeModelObject::batch_init`.
+     *
+     * * `pipe_name`: The name of the pipe
+     * * `pipe`: The pipe we want to access
+     * * `path`: The path to the field
+     * * `validate_type`: If we want to validate the type shape
+     *
+     * Returns the initialization parameter, which borrows the field until the initialization is done
+     **/
+    fn init_param<'a>(&'a mut self, pipe_name:&'a str, pipe:PipeDescriptor, path:&'a str, validate_type:bool) -> FieldInitParam<'a>;
+}
+
+impl <T : PrimitiveTypeTag<T> + Default> ProtocolField for Primitive<T> {
+    type Value = T;
+    fn new_field() -> Self { return Primitive::new(); }
+    fn read_value(&self, type_inst:&mut TypeInstanceObject) -> Result<T, FieldError>
+    {
+        return self.try_get(type_inst);
+    }
+    fn write_value(&self, type_inst:&mut TypeInstanceObject, val:&T) -> Result<(), FieldError>
+    {
+        return self.write_ref(type_inst, val);
+    }
+    fn is_present(&self, type_inst:&mut TypeInstanceObject) -> bool
+    {
+        return Primitive::is_present(self, type_inst);
+    }
+    fn init_param<'a>(&'a mut self, pipe_name:&'a str, pipe:PipeDescriptor, path:&'a str, validate_type:bool) -> FieldInitParam<'a>
+    {
+        return FieldInitParam {
+            pipe_name : pipe_name,
+            pipe      : pipe,
+            path      : path,
+            accessor  : &mut self.accessor as *mut Option<pstd_type_accessor_t>,
+            shape     : &mut self.shape as *mut PrimitiveTypeShape,
+            validate  : if validate_type { Some(T::validate_type_shape) } else { None }
+        };
+    }
+}
+
+impl <T : PrimitiveTypeTag<T> + Default + Copy> ProtocolField for PrimitiveArray<T> {
+    type Value = Vec<T>;
+    fn new_field() -> Self { return PrimitiveArray::new(); }
+    fn read_value(&self, type_inst:&mut TypeInstanceObject) -> Result<Vec<T>, FieldError>
+    {
+        return self.try_get(type_inst);
+    }
+    fn write_value(&self, type_inst:&mut TypeInstanceObject, val:&Vec<T>) -> Result<(), FieldError>
+    {
+        return self.try_set(type_inst, &val[..]);
+    }
+    fn is_present(&self, type_inst:&mut TypeInstanceObject) -> bool
+    {
+        return PrimitiveArray::is_present(self, type_inst);
+    }
+    fn init_param<'a>(&'a mut self, pipe_name:&'a str, pipe:PipeDescriptor, path:&'a str, validate_type:bool) -> FieldInitParam<'a>
+    {
+        return FieldInitParam {
+            pipe_name : pipe_name,
+            pipe      : pipe,
+            path      : path,
+            accessor  : &mut self.accessor as *mut Option<pstd_type_accessor_t>,
+            shape     : &mut self.shape as *mut PrimitiveTypeShape,
+            validate  : if validate_type { Some(PrimitiveArray::<T>::validate_type_shape) } else { None }
+        };
+    }
+}
+
+/**
+ * The accessor of a field within the current task context.
+ *
+ * The accessor binds a field descriptor from the protocol model with the type instance of the
+ * current task, so that the field can be read or written without passing the type instance
+ * around. The accessors are created by the data model generated with `protodef!`.
+ **/
+pub struct FieldAccessor<'a, F: ProtocolField + 'a> {
+    /// The field descriptor
+    target : &'a F,
+    /// The type instance of current task
+    inst   : &'a mut TypeInstanceObject
+}
+
+impl <'a, F: ProtocolField> FieldAccessor<'a, F> {
+    /**
+     * Create a new field accessor
+     *
+     * * `target`: The field descriptor
+     * * `inst`: The type instance of current task
+     *
+     * Returns the newly created accessor
+     **/
+    pub fn new(target:&'a F, inst:&'a mut TypeInstanceObject) -> FieldAccessor<'a, F>
+    {
+        return FieldAccessor {
+            target : target,
+            inst   : inst
+        };
+    }
 
-        unsafe{ pstd_type_model_on_pipe_type_checked(self.object, 
-                                                     pipe, 
-                                                     Some(_validate_primitive_type_shape::<T>), 
-                                                     check_shape_ref as *mut std::os::raw::c_void) };
-        return true;
+    /**
+     * Read the field.
+     *
+     * Returns the value or None if the field cannot be read
+     **/
+    pub fn get(&mut self) -> Option<F::Value>
+    {
+        return self.target.read_value(self.inst).ok();
     }
 
     /**
-     * Assign a primitive data object to the type model. This will cause the Plumber framework
-     * check the protocol database and keep the type information in the primitive object for
-     * further protocol parsing
+     * Read the field and use the default value when the field cannot be read
      *
-     * * `pipe`: The pipe we want to access
-     * * `path`: The path to the pipe 
-     * * `primitive`: The primitive object
-     * * `validate_type` If we want to validate the type shape
+     * * `default`: The default value
      *
-     * Returns if the operation has sucessfully completed
+     * Returns the value of the field or the default value
      **/
-    pub fn assign_primitive<'a, 'b, T>(&self, 
-                                          pipe:PipeDescriptor, 
-                                          path:&'a str, 
-                                          primitive:&'b mut Primitive<T>, 
-                                          validate_type:bool) -> bool 
-        where T : PrimitiveTypeTag<T> + Default
+    pub fn get_or(&mut self, default:F::Value) -> F::Value
     {
-        if let None = primitive.accessor 
-        {
-            let (c_path, _path) = get_cstr(Some(path));
-
-            if validate_type && !self._add_type_shape_check(pipe, c_path, primitive)
-            {
-                return false;
-            }
-
-            let accessor = unsafe { pstd_type_model_get_accessor(self.object, pipe, c_path) };
-
-            if accessor as i32 == -1 
-            {
-                return false;
-            }
+        return self.target.read_value(self.inst).unwrap_or(default);
+    }
 
-            let mut new_val = Some(accessor);
+    /**
+     * Read the field and report the reason of failure
+     *
+     * Returns the value or the error
+     **/
+    pub fn try_get(&mut self) -> Result<F::Value, FieldError>
+    {
+        return self.target.read_value(self.inst);
+    }
 
-            std::mem::swap(&mut primitive.accessor, &mut new_val);
+    /**
+     * Read the field, modify it with the given function and then write the result back
+     *
+     * * `what`: The function that computes the new value from the current value
+     *
+     * Returns if the operation has sucessfully done
+     **/
+    pub fn update<U>(&mut self, what:U) -> bool where U : FnOnce(F::Value) -> F::Value
+    {
+        return self.try_update(what).is_ok();
+    }
 
-            return true;
-        }
+    /**
+     * Update the field and report the reason of failure. See `update` for details.
+     *
+     * * `what`: The function that computes the new value from the current value
+     *
+     * Returns the operation result or the error
+     **/
+    pub fn try_update<U>(&mut self, what:U) -> Result<(), FieldError> where U : FnOnce(F::Value) -> F::Value
+    {
+        let value = what(self.target.read_value(self.inst)?);
+        return self.target.write_value(self.inst, &value);
+    }
 
-        return false;
+    /**
+     * Check if the field is present in current task, which means the field has been assigned to
+     * the type model and the type instance has the data of the field.
+     *
+     * Returns the check result
+     **/
+    pub fn is_present(&mut self) -> bool
+    {
+        return self.target.is_present(self.inst);
     }
-}
 
-/**
- * The object used to represent a pritmive type in the language-neutral protocol database
- **/
-pub struct Primitive<ActualType : PrimitiveTypeTag<ActualType> + Default> {
-    /// The type accessor object
-    accessor : Option<pstd_type_accessor_t>,
-    /// The shape of this primmitive
-    shape    : PrimitiveTypeShape,
-    /// The type holder
-    _phantom : PhantomData<ActualType>
 }
 
-impl <T : PrimitiveTypeTag<T> + Default> Primitive<T> {
+impl <'a, T: PrimitiveTypeTag<T> + Default> FieldAccessor<'a, Primitive<T>> {
     /**
-     * Create a new type primitive
+     * Write the field
+     *
+     * * `val`: The value to write
+     *
+     * Returns if the operation has successfully done
      **/
-    pub fn new() -> Primitive<T>
+    pub fn set(&mut self, val:T) -> bool 
     {
-        return Primitive {
-            accessor : None,
-            shape    : Default::default(),
-            _phantom : PhantomData
-        };
+        return self.target.set(self.inst, val);
     }
 
     /**
-     * Get a primitive value from the primitive descriptor. 
+     * Write the field and report the reason of failure
      *
-     * This function will be valid only when it's called from execution function and there's
-     * type instance object has been created. Otherwise it will returns a failure
+     * * `val`: The value to write
      *
-     * * `type_inst`: Type instance object where we read the primitive from
-     * 
-     * Return the read result, None indicates we are unable to read the data
+     * Returns the operation result or the error
      **/
-    pub fn get(&self, type_inst:&mut TypeInstanceObject) -> Option<T>
+    pub fn try_set(&mut self, val:T) -> Result<(), FieldError>
     {
-        if let Some(ref acc_ref) = self.accessor
-        {
-            let mut buf:T = Default::default();
-            let mut buf_ptr = &mut buf as *mut T;
-            let acc = acc_ref.clone();
-
-            if type_inst.read(acc, buf_ptr as *mut std::os::raw::c_void, std::mem::size_of::<T>())
-            {
-                return Some(buf);
-            }
-        }
-
-        return None;
+        return self.target.try_set(self.inst, val);
     }
+}
 
+impl <'a, T: PrimitiveTypeTag<T> + Default + Copy> FieldAccessor<'a, PrimitiveArray<T>> {
     /**
-     * Write a primitive to the primitive descriptor within current task context
+     * Write the array field, the length of the slice must be the capacity of the field
      *
-     * This function will be valid only when it's called from the execution function, because it
-     * requires the task context.
+     * * `val`: The array to write
      *
-     * * `type_inst`: The type instance object where we want to write to
+     * Returns if the operation has successfully done
+     **/
+    pub fn set(&mut self, val:&[T]) -> bool 
+    {
+        return self.target.set(self.inst, val);
+    }
+
+    /**
+     * Write the array field and report the reason of failure
      *
-     * Return the operation result, if the operation has successuflly  done.
+     * * `val`: The array to write
+     *
+     * Returns the operation result or the error
      **/
-    pub fn set(&self, type_inst:&mut TypeInstanceObject, val:T) -> bool
+    pub fn try_set(&mut self, val:&[T]) -> Result<(), FieldError>
     {
-        if let Some(ref acc_ref) = self.accessor
-        {
-            let acc = acc_ref.clone();
-            let val_ref = &val;
-            let val_ptr = val_ref as *const T;
+        return self.target.try_set(self.inst, val);
+    }
 
-            if type_inst.write(acc, val_ptr as *mut std::os::raw::c_void, std::mem::size_of::<T>())
-            {
-                return true;
-            }
-        }
-        return false;
+    /**
+     * Get the number of elements of the array field declared in the protocol
+     **/
+    pub fn capacity(&self) -> usize
+    {
+        return self.target.capacity();
     }
+}
 
+/**
+ * The trait that maps a Rust type used in the `protodef!` macro to the field descriptor type.
+ *
+ * For a primitive type `T`, the field descriptor is `Primitive<T>`, and for a slice type `[T]`,
+ * the field descriptor is `PrimitiveArray<T>`.
+ **/
+pub trait FieldTypeTag {
+    /// The field descriptor type
+    type Field : ProtocolField;
 }
 
 /**
@@ -373,6 +1215,12 @@ macro_rules! primitive_type {
             {
                 return $((ts.$var() == $val)&&)* true;
             }
+        }
+        impl FieldTypeTag for $type {
+            type Field = Primitive<$type>;
+        }
+        impl FieldTypeTag for [$type] {
+            type Field = PrimitiveArray<$type>;
         })*
     }
 }
@@ -436,9 +1284,9 @@ pub trait ProtocolModel {
      *
      * * `pipes`: A map that contains the map from the pipe name to pipe descriptor
      *
-     * Returns if or not this model has been successfully initialized
+     * Returns the initialization result, or the error describes which pipe or field failed
      **/
-    fn init_model(&mut self, pipes: HashMap<String, PipeDescriptor>) -> bool;
+    fn init_model(&mut self, pipes: HashMap<String, PipeDescriptor>) -> Result<(), ProtocolInitError>;
 
     /**
      * Create a new protocol model, which is the high-level wrapper of the Type Model object
@@ -450,6 +1298,21 @@ pub trait ProtocolModel {
     fn new_protocol_model(type_model:TypeModelObject) -> Self;
 }
 
+/**
+ * The trait that links a protocol model with the types generated for it.
+ *
+ * The `protodef!` macro generates the data model and the plain data struct without exposing
+ * their names, because a crate may define multiple protocols in different modules. This trait is
+ * the way to refer those types from the protocol model, for example `use_protocol!` uses
+ * `<MyProtocol as TypedProtocol>::DataModelType` as the data model of the servlet.
+ **/
+pub trait TypedProtocol : ProtocolModel + Sized {
+    /// The data model type used to access the typed data in the execution function
+    type DataModelType : DataModel<Self>;
+    /// The plain struct that carries all the fields of the protocol
+    type DataType;
+}
+
 /**
  * The placeholder for the data model and protocol model of a totally untyped servlet.
  *
@@ -459,7 +1322,7 @@ pub trait ProtocolModel {
 pub type Untyped = ();
 
 impl ProtocolModel for Untyped {
-    fn init_model(&mut self, _p:HashMap<String, PipeDescriptor>) -> bool { true }
+    fn init_model(&mut self, _p:HashMap<String, PipeDescriptor>) -> Result<(), ProtocolInitError> { Ok(()) }
     fn new_protocol_model(_tm:TypeModelObject) -> Untyped {}
 }
 
@@ -467,6 +1330,11 @@ impl DataModel<Untyped> for Untyped {
     fn new_data_model(_m : Rc<Untyped>, _ti: TypeInstanceObject) -> Untyped {}
 }
 
+impl TypedProtocol for Untyped {
+    type DataModelType = Untyped;
+    type DataType      = Untyped;
+}
+
 // TODO: how to handle the writer ?
 //
 // Also we need to handle the token type 
@@ -517,6 +1385,21 @@ impl DataModel<Untyped> for Untyped {
  *      let y = data_model.input_y().get();    // read y
  * ```
  *
+ * Besides `get` and `set`, the field accessor also provides `get_or`, `update`, `is_present` and
+ * the error-returning variants `try_get`, `try_set` and `try_update`, which report the `FieldError`:
+ * ```
+ *      let x = data_model.input_x().try_get().map_err(|err| { plumber_log!(E "{}", err); })?;
+ * ```
+ *
+ * All the fields can be also accessed at once with the plain struct
+ * `<MyProtocol as TypedProtocol>::DataType`, which has an `Option` field for each field in the
+ * protocol:
+ * ```
+ *      let data = data_model.read_all();
+ *      ...
+ *      data_model.write_all(&data).map_err(|_| ())?;
+ * ```
+ *
  * In order to make the compiler knows our servlet actually use a specified protcol. The
  * `use_protocol!` macro should be used inside the servlet implementation. For example
  *
@@ -526,11 +1409,41 @@ impl DataModel<Untyped> for Untyped {
  *      ......
  * }
  * ```
+ *
+ * The protocol model is defined as a public type with the protocol name at the place where the
+ * macro is invoked. So multiple protocols can be defined in different modules of the same crate,
+ * and the servlet refers the protocol with its path, for example `use_protocol!(protocols::MyProtocol)`.
  * The mapping syntax is as following:
  * ```
  *  [field.path.to.plumber]:rust_type => rust_identifer
  * ```
  *
+ * An array field can be mapped with a slice type. The size of the field is determined at runtime,
+ * so the variable-width fields are also supported. Reading an array field returns a `Vec` of the
+ * elements, and writing an array field requires a slice with exactly the length defined by the
+ * protocol:
+ * ```
+ * protodef!{
+ *    protodef MyProtocol {
+ *      [input.samples]:[f32] => samples;
+ *    }
+ * }
+ * ```
+ *
+ * For a filtering servlet which forwards the input to the output with only a few fields changed,
+ * a copy directive can be put before the field mappings. This makes libpstd copy the typed data
+ * of the input pipe to the output pipe for each execution, so only the modified fields needs to
+ * be mapped:
+ * ```
+ * protodef!{
+ *    protodef MyFilter {
+ *      copy [input] => [output];
+ *      [output.x]:f32 => output_x;
+ *    }
+ * }
+ * ```
+ * Both pipes in the copy directive must be assigned with `init_protocol!`.
+ *
  * Limit: 
  * * Currently we do not support compound object access, for example, we can not read the entire
  * `Point2D` object
@@ -538,110 +1451,140 @@ impl DataModel<Untyped> for Untyped {
  **/
 #[macro_export]
 macro_rules! protodef {
-    ($(protodef $proto_name:ident { $([$pipe:ident.$($field:tt)*]:$type:ty => $model_name:ident;)* })*) => {
-        mod plumber_protocol {
-            use crate::plumber_rs::protocol::{Primitive, TypeModelObject, ProtocolModel};
-            use crate::plumber_rs::pipe::PipeDescriptor;
+    ($(protodef $proto_name:ident { $(copy [$copy_from:ident] => [$copy_to:ident];)* $([$pipe:ident.$($field:tt)*]:$type:ty => $model_name:ident;)* })*) => {
+        $(
+        pub struct $proto_name {
+            type_model : $crate::protocol::TypeModelObject,
+            $(pub $model_name : <$type as $crate::protocol::FieldTypeTag>::Field,)*
+        }
+
+        const _ : () = {
+            use $crate::protocol::{TypeModelObject, TypeInstanceObject, ProtocolModel, DataModel, TypedProtocol, 
+                                   ProtocolField, FieldTypeTag, FieldAccessor, FieldError, ProtocolInitError};
+            use $crate::pipe::PipeDescriptor;
             use std::collections::HashMap;
-            $(
-            pub struct $proto_name {
-                type_model : TypeModelObject,
-                $(pub $model_name : Primitive<$type>,)*
-            }
+            use std::rc::Rc;
+
             impl ProtocolModel for $proto_name {
                 fn init_model(&mut self, 
-                              pipes: HashMap<String, PipeDescriptor>) -> bool 
+                              pipes: HashMap<String, PipeDescriptor>) -> Result<(), ProtocolInitError>
                 {
+                    let get_pipe = |name:&str| {
+                        return pipes.get(name).map(|pipe| *pipe).ok_or(ProtocolInitError::MissingPipe(name.to_string()));
+                    };
+
+                    let params = vec![$(
+                        self.$model_name.init_param(stringify!($pipe), get_pipe(stringify!($pipe))?, stringify!($($field)*), true),
+                    )*];
+
+                    self.type_model.batch_init(&params[..], file!(), line!())?;
+
                     $(
-                        if let Some(pipe) = pipes.get(stringify!($pipe))
-                        {
-                            if !self.type_model.assign_primitive(*pipe, stringify!($($field)*), &mut self.$model_name, true)
-                            {
-                                return false;
-                            }
-                        }
-                        else
+                        if !self.type_model.copy_pipe_data(get_pipe(stringify!($copy_from))?, get_pipe(stringify!($copy_to))?)
                         {
-                            return false;
+                            return Err(ProtocolInitError::CopyFailure {
+                                from : stringify!($copy_from).to_string(),
+                                to   : stringify!($copy_to).to_string()
+                            });
                         }
                     )*
-                    return true;
+                    return Ok(());
                 }
                 fn new_protocol_model(type_model : TypeModelObject) -> Self
                 {
                     return $proto_name {
                         type_model : type_model,
                         $(
-                            $model_name : Primitive::new()
+                            $model_name : ProtocolField::new_field()
                         ),*
                     };
                 }
             }
-            )*
-        }
-        mod plumber_protocol_accessor {
-            use crate::plumber_rs::protocol::{DataModel, TypeInstanceObject, PrimitiveTypeTag, Primitive};
-            use std::rc::Rc;
-            pub struct FieldAccessor<'a, T: PrimitiveTypeTag<T> + Default + 'a> {
-                target : &'a Primitive<T>,
-                inst   : &'a mut TypeInstanceObject
-            }
 
-            impl <'a, T: PrimitiveTypeTag<T> + Default> FieldAccessor<'a, T> {
-                pub fn get(&mut self) -> Option<T>
-                {
-                    return self.target.get(self.inst);
-                }
-
-                pub fn set(&mut self, val:T) -> bool 
-                {
-                    return self.target.set(self.inst, val);
-                }
+            pub struct Accessor {
+                model : Rc<$proto_name>,
+                inst  : TypeInstanceObject
             }
 
-            $(
-            pub struct $proto_name {
-                model : Rc<crate::plumber_protocol::$proto_name>,
-                inst  : TypeInstanceObject
+            #[derive(Default, Debug, Clone)]
+            pub struct Data {
+                $(pub $model_name : Option<<<$type as FieldTypeTag>::Field as ProtocolField>::Value>,)*
             }
 
-            impl $proto_name {
+            impl Accessor {
                 $(
                     #[allow(dead_code)]
-                    pub fn $model_name(&mut self) -> FieldAccessor<$type>
+                    pub fn $model_name(&mut self) -> FieldAccessor<<$type as FieldTypeTag>::Field>
                     {
-                        return FieldAccessor::<$type>{
-                            target: &self.model.$model_name,
-                            inst  : &mut self.inst
-                        };
+                        return FieldAccessor::new(&self.model.$model_name, &mut self.inst);
                     }
                 )*
+
+                /**
+                 * Read all the fields in the protocol. The fields can not be read, for example
+                 * the fields of output pipes, are left as None.
+                 **/
+                #[allow(dead_code)]
+                pub fn read_all(&mut self) -> Data
+                {
+                    return Data {
+                        $($model_name : self.model.$model_name.read_value(&mut self.inst).ok(),)*
+                    };
+                }
+
+                /**
+                 * Write all the fields in the protocol which has a value in the data object.
+                 **/
+                #[allow(dead_code)]
+                pub fn write_all(&mut self, data:&Data) -> Result<(), FieldError>
+                {
+                    $(
+                        if let Some(ref value) = data.$model_name
+                        {
+                            self.model.$model_name.write_value(&mut self.inst, value)?;
+                        }
+                    )*
+                    return Ok(());
+                }
             }
 
-            impl DataModel<crate::plumber_protocol::$proto_name> for $proto_name {
-                fn new_data_model(model : Rc<crate::plumber_protocol::$proto_name>, type_inst:TypeInstanceObject) -> $proto_name
+            impl DataModel<$proto_name> for Accessor {
+                fn new_data_model(model : Rc<$proto_name>, type_inst:TypeInstanceObject) -> Accessor
                 {
-                    return $proto_name{
+                    return Accessor {
                         model : model,
                         inst  : type_inst
                     };
                 }
             }
-            )*
-        }
+
+            impl TypedProtocol for $proto_name {
+                type DataModelType = Accessor;
+                type DataType      = Data;
+            }
+        };
+        )*
     }
 }
 
 /**
  * Make the servlet implementation uses the given protocol defined by `protodef!`
  *
- * This should be  use inside the servlet implementation block. 
+ * This should be  use inside the servlet implementation block. The protocol can be referred by
+ * any path, so the protocol can be defined in other modules. For example:
+ *
+ * ```
+ * impl SyncServlet for MyServlet {
+ *      use_protocol!(protocols::MyProtocol);
+ *      ......
+ * }
+ * ```
  **/
 #[macro_export]
 macro_rules! use_protocol {
-    ($name:ident) => {
-        type ProtocolType   = crate::plumber_protocol::$name;
-        type DataModelType  = crate::plumber_protocol_accessor::$name;
+    ($name:path) => {
+        type ProtocolType   = $name;
+        type DataModelType  = <$name as $crate::protocol::TypedProtocol>::DataModelType;
     }
 }
 
@@ -653,8 +1596,8 @@ macro_rules! use_protocol {
 #[macro_export]
 macro_rules! no_protocol {
     () => {
-        type ProtocolType = crate::plumber_rs::protocol::Untyped;
-        type DataModelType = crate::plumber_rs::protocol::Untyped;
+        type ProtocolType = $crate::protocol::Untyped;
+        type DataModelType = $crate::protocol::Untyped;
     }
 }
 
@@ -671,18 +1614,193 @@ macro_rules! no_protocol {
  * }
  * ```
  *
+ * If all the pipes are declared with `pipe_set!`, the pipe set can be used directly, and each
+ * pipe is assigned by its pipe name:
+ *
+ * ```
+ * init_protocol!(model_object from self.pipes);
+ * ```
+ *
  * For details please read the `protodef!` doc
  **/
 #[macro_export]
 macro_rules! init_protocol {
+    (@init $what:ident, $pipe_map:expr) => {
+        if let Err(err) = $what.init_model($pipe_map)
+        {
+            $crate::log::log_write(1, file!(), line!() as i32, &format!("Cannot initialize the protocol: {}", err));
+            return $crate::servlet::fail();
+        }
+    };
+    ($what:ident from $pipes:expr) => {
+        $crate::init_protocol!(@init $what, $crate::pipe::PipeSet::pipe_map(&$pipes));
+    };
     ($what:ident {$($actual:expr => $model:ident),*}) => {
         {
-            let mut pipe_map = crate::std::collections::HashMap::<String, crate::plumber_rs::pipe::PipeDescriptor>::new();
+            let mut pipe_map = ::std::collections::HashMap::<String, $crate::pipe::PipeDescriptor>::new();
             $(pipe_map.insert(stringify!($model).to_string(), $actual.as_descriptor());)*
-            if !$what.init_model(pipe_map)
+            $crate::init_protocol!(@init $what, pipe_map);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// A field that keeps its value in memory, so the accessors can be tested without libpstd
+    pub struct MockField {
+        value    : Cell<Option<u32>>,
+        writable : bool
+    }
+
+    impl ProtocolField for MockField {
+        type Value = u32;
+        fn new_field() -> Self { return MockField { value : Cell::new(None), writable : true }; }
+        fn read_value(&self, _type_inst:&mut TypeInstanceObject) -> Result<u32, FieldError>
+        {
+            return self.value.get().ok_or(FieldError::ReadFailure);
+        }
+        fn write_value(&self, _type_inst:&mut TypeInstanceObject, val:&u32) -> Result<(), FieldError>
+        {
+            if !self.writable
             {
-                return crate::plumber_rs::servlet::fail();
+                return Err(FieldError::WriteFailure);
             }
+            self.value.set(Some(*val));
+            return Ok(());
+        }
+        fn is_present(&self, _type_inst:&mut TypeInstanceObject) -> bool
+        {
+            return self.value.get().is_some();
+        }
+        fn init_param<'a>(&'a mut self, pipe_name:&'a str, pipe:PipeDescriptor, path:&'a str, _validate_type:bool) -> FieldInitParam<'a>
+        {
+            return FieldInitParam {
+                pipe_name : pipe_name,
+                pipe      : pipe,
+                path      : path,
+                accessor  : std::ptr::null_mut(),
+                shape     : std::ptr::null_mut(),
+                validate  : None
+            };
+        }
+    }
+
+    pub struct Mock;
+
+    impl FieldTypeTag for Mock {
+        type Field = MockField;
+    }
+
+    protodef! {
+        protodef MockProtocol {
+            [input.x]:Mock => x;
+            [output.y]:Mock => y;
+        }
+    }
+
+    fn dummy_instance(buffer:&mut u64) -> TypeInstanceObject
+    {
+        return TypeInstanceObject::from_raw(buffer as *mut u64 as *mut crate::std::os::raw::c_void).unwrap();
+    }
+
+    fn mock_field(value:Option<u32>, writable:bool) -> MockField
+    {
+        return MockField { value : Cell::new(value), writable : writable };
+    }
+
+    #[test]
+    fn get_or_falls_back_to_default()
+    {
+        let mut buffer = 0u64;
+        let mut inst = dummy_instance(&mut buffer);
+
+        let field = mock_field(Some(3), true);
+        assert_eq!(FieldAccessor::new(&field, &mut inst).get_or(7), 3);
+
+        let missing = mock_field(None, true);
+        assert_eq!(FieldAccessor::new(&missing, &mut inst).get_or(7), 7);
+    }
+
+    #[test]
+    fn update_writes_back_the_new_value()
+    {
+        let mut buffer = 0u64;
+        let mut inst = dummy_instance(&mut buffer);
+
+        let field = mock_field(Some(3), true);
+        assert!(FieldAccessor::new(&field, &mut inst).update(|x| x * 2));
+        assert_eq!(field.value.get(), Some(6));
+
+        let missing = mock_field(None, true);
+        assert_eq!(FieldAccessor::new(&missing, &mut inst).try_update(|x| x + 1), Err(FieldError::ReadFailure));
+        assert_eq!(missing.value.get(), None);
+
+        let readonly = mock_field(Some(3), false);
+        assert!(!FieldAccessor::new(&readonly, &mut inst).update(|x| x + 1));
+        assert_eq!(readonly.value.get(), Some(3));
+    }
+
+    #[test]
+    fn read_all_and_write_all_use_the_data_struct()
+    {
+        let mut buffer = 0u64;
+        let type_model = TypeModelObject::from_raw(&mut buffer as *mut u64 as *mut crate::std::os::raw::c_void).unwrap();
+        let mut model = MockProtocol::new_protocol_model(type_model);
+        model.x.value.set(Some(5));
+        model.y.writable = false;
+
+        let model = Rc::new(model);
+        let mut accessor = <MockProtocol as TypedProtocol>::DataModelType::new_data_model(model.clone(), dummy_instance(&mut buffer));
+
+        let mut data = accessor.read_all();
+        assert_eq!(data.x, Some(5));
+        assert_eq!(data.y, None);
+
+        data.x = Some(8);
+        assert_eq!(accessor.write_all(&data), Ok(()));
+        assert_eq!(model.x.value.get(), Some(8));
+
+        data.y = Some(1);
+        assert_eq!(accessor.write_all(&data), Err(FieldError::WriteFailure));
+        assert_eq!(model.y.value.get(), None);
+    }
+
+    #[test]
+    fn unassigned_field_is_not_present()
+    {
+        let mut buffer = 0u64;
+        let mut inst = TypeInstanceObject::from_raw(&mut buffer as *mut u64 as *mut crate::std::os::raw::c_void).unwrap();
+
+        let field = Primitive::<u32>::new();
+        assert!(!FieldAccessor::new(&field, &mut inst).is_present());
+
+        let mut failed = Primitive::<u32>::new();
+        failed.accessor = Some(ERROR_ACCESSOR);
+        assert!(!FieldAccessor::new(&failed, &mut inst).is_present());
+
+        let array = PrimitiveArray::<u8>::new();
+        assert!(!FieldAccessor::new(&array, &mut inst).is_present());
+    }
+
+    #[test]
+    fn failed_init_resets_accessors()
+    {
+        let mut field = Primitive::<u32>::new();
+        let mut array = PrimitiveArray::<u8>::new();
+
+        field.accessor = Some(1);
+        array.accessor = Some(ERROR_ACCESSOR);
+
+        {
+            let params = [field.init_param("in", 0, "value", true), array.init_param("in", 0, "data", true)];
+            TypeModelObject::reset_accessors(&params[..]);
         }
+
+        assert!(field.accessor.is_none());
+        assert!(array.accessor.is_none());
     }
 }
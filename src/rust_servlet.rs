@@ -6,9 +6,13 @@
 use std::os::raw::{c_char, c_void};
 use std::ffi::CStr;
 use std::ptr::null;
+use std::any::type_name;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use crate::servlet::{Unimplemented, AsyncServlet, SyncServlet, ServletMode, ServletFuncResult, Bootstrap, AsyncTaskHandle, fail, BootstrapResult};
 use crate::protocol::{TypeModelObject, TypeInstanceObject, Untyped, ProtocolModel, DataModel};
+use crate::log::{log_write, reload_log_level};
 
 impl SyncServlet for Unimplemented {
     type ProtocolType = Untyped;
@@ -63,35 +67,68 @@ impl AsyncServlet for Unimplemented {
 
 struct SyncServletObject<BT:Bootstrap> {
     protocol_model : Rc<<BT::SyncServletType as SyncServlet>::ProtocolType>,
-    servlet_context: BT::SyncServletType
+    servlet_context: BT::SyncServletType,
+    servlet_name   : Arc<str>,
+    activations    : AtomicUsize
 }
 
 struct AsyncServletObject<BT:Bootstrap> {
     protocol_model : Rc<<BT::AsyncServletType as AsyncServlet>::ProtocolType>,
-    servlet_context: BT::AsyncServletType
+    servlet_context: BT::AsyncServletType,
+    servlet_name   : Arc<str>,
+    activations    : AtomicUsize
 }
 
+/**
+ * The object that carries the async task private data, along with the activation it belongs to
+ **/
+struct AsyncTaskObject<BT:Bootstrap> {
+    servlet_name: Arc<str>,
+    activation  : usize,
+    task_data   : Box<<BT::AsyncServletType as AsyncServlet>::AsyncTaskData>
+}
+
+#[cfg(feature = "tracing-layer")]
+type ActivationSpan = crate::trace::ActivationSpan;
+
+#[cfg(not(feature = "tracing-layer"))]
+type ActivationSpan = ();
+
+#[cfg(feature = "tracing-layer")]
+fn enter_activation(kind:&str, servlet:&str, activation:usize) -> ActivationSpan
+{
+    return crate::trace::enter_activation(kind, servlet, activation);
+}
+
+#[cfg(not(feature = "tracing-layer"))]
+fn enter_activation(_kind:&str, _servlet:&str, _activation:usize) -> ActivationSpan {}
+
 enum ServletObject<BT:Bootstrap> {
     SYNC(SyncServletObject<BT>),
     ASYNC(AsyncServletObject<BT>)
 }
 
 fn create_servlet_object<BT:Bootstrap>(bs_result:ServletMode<BT::AsyncServletType, BT::SyncServletType>, 
-                                       type_model_obj: TypeModelObject) -> ServletObject<BT>
+                                       type_model_obj: TypeModelObject,
+                                       servlet_name: &str) -> ServletObject<BT>
 {
     match bs_result {
         ServletMode::SyncMode(servlet) => {
             let protocol_model = <BT::SyncServletType as SyncServlet>::ProtocolType::new_protocol_model(type_model_obj);
             return ServletObject::SYNC(SyncServletObject {
                 protocol_model : Rc::new(protocol_model),
-                servlet_context: servlet
+                servlet_context: servlet,
+                servlet_name   : Arc::from(servlet_name),
+                activations    : AtomicUsize::new(0)
             });
         },
         ServletMode::AsyncMode(servlet) => {
             let protocol_model = <BT::AsyncServletType as AsyncServlet>::ProtocolType::new_protocol_model(type_model_obj);
             return ServletObject::ASYNC(AsyncServletObject {
                 protocol_model : Rc::new(protocol_model),
-                servlet_context: servlet
+                servlet_context: servlet,
+                servlet_name   : Arc::from(servlet_name),
+                activations    : AtomicUsize::new(0)
             });
         }
     }
@@ -146,14 +183,14 @@ unsafe fn unpack_async_handle<'a>(handle_ptr : *mut c_void) -> &'a AsyncTaskHand
     unpack(handle_ptr)
 }
 
-unsafe fn unpack_async_task_data<'a, BT:Bootstrap>(data_ptr : *mut c_void) -> &'a mut <<BT as Bootstrap>::AsyncServletType as AsyncServlet>::AsyncTaskData
+unsafe fn unpack_async_task_data<'a, BT:Bootstrap>(data_ptr : *mut c_void) -> &'a mut AsyncTaskObject<BT>
 {
     unpack(data_ptr)
 }
 
 unsafe fn dispose_async_task_data<BT:Bootstrap>(obj_ptr : *mut c_void)
 {
-    dispose::<<<BT as Bootstrap>::AsyncServletType as AsyncServlet>::AsyncTaskData>(obj_ptr);
+    dispose::<AsyncTaskObject<BT>>(obj_ptr);
 }
 
 /**
@@ -176,13 +213,34 @@ unsafe fn dispose_async_task_data<BT:Bootstrap>(obj_ptr : *mut c_void)
  **/
 pub unsafe fn call_bootstrap_obj<T:Bootstrap>(argc: u32, argv: *const *const c_char, type_model_ptr:*mut c_void) -> *mut c_void
 {
+    return call_named_bootstrap_obj::<T>(type_name::<T>(), argc, argv, type_model_ptr);
+}
+
+/**
+ * Call the bootstrap object for the servlet with the given servlet name, which is used to identify
+ * the servlet in the tracing spans. `call_bootstrap_obj` uses the bootstrap type name, and
+ * `export_servlets!` uses the name the servlet is exported with.
+ *
+ * DO NOT use this function directly, see `call_bootstrap_obj`
+ *
+ * * `servlet_name`: The name of the servlet
+ * * `argc`: The number of servlet initailization arguments
+ * * `argv`: The list of servlet initialization arguments
+ * * `type_model_ptr`: The pointer points to the actual type model allocated by the loader
+ *
+ * Returns a raw pointer to the actual servlet object
+ **/
+pub unsafe fn call_named_bootstrap_obj<T:Bootstrap>(servlet_name: &str, argc: u32, argv: *const *const c_char, type_model_ptr:*mut c_void) -> *mut c_void
+{
+    reload_log_level();
+
     if let Some(type_model) = TypeModelObject::from_raw(type_model_ptr as *mut c_void) 
     {
         if let Some(args) = make_argument_list(argc, argv)
         {
             if let BootstrapResult::Success(servlet_mode) = T::get(&args[0..]) 
             {
-                let result_obj = Box::new(create_servlet_object::<T>(servlet_mode, type_model));
+                let result_obj = Box::new(create_servlet_object::<T>(servlet_mode, type_model, servlet_name));
 
                 return Box::into_raw(result_obj) as *mut c_void;
             }
@@ -253,6 +311,8 @@ pub fn invoke_servlet_sync_exec<BT:Bootstrap>(obj_ptr : *mut c_void, type_inst :
     {
         if let ServletObject::SYNC(ref mut servlet) = unsafe { unpack_servlet_object::<BT>(obj_ptr) } 
         {
+            let activation = servlet.activations.fetch_add(1, Ordering::Relaxed);
+            let _span = enter_activation("exec", &servlet.servlet_name, activation);
             let accessor = <BT::SyncServletType as SyncServlet>::DataModelType::new_data_model(Rc::clone(&servlet.protocol_model), type_inst_obj);
             if let Ok(_) = servlet.servlet_context.exec(accessor)
             {
@@ -277,6 +337,7 @@ pub fn invoke_servlet_sync_exec<BT:Bootstrap>(obj_ptr : *mut c_void, type_inst :
 pub fn invoke_servlet_cleanup<BT:Bootstrap>(obj_ptr : *mut c_void) -> i32
 {
     let mut ret = -1;
+
     match unsafe { unpack_servlet_object::<BT>(obj_ptr) } 
     {
         ServletObject::SYNC(ref mut servlet) => {
@@ -321,12 +382,20 @@ pub fn invoke_servlet_async_init<BT:Bootstrap>(obj_ptr : *mut c_void, handle_ptr
         if let ServletObject::ASYNC(ref mut servlet) = unsafe { unpack_servlet_object::<BT>(obj_ptr) }
         {
             let handle = unsafe{unpack_async_handle(handle_ptr)};
+
+            let activation = servlet.activations.fetch_add(1, Ordering::Relaxed);
+            let _span = enter_activation("async_init", &servlet.servlet_name, activation);
             
             let accessor = <BT::AsyncServletType as AsyncServlet>::DataModelType::new_data_model(Rc::clone(&servlet.protocol_model), type_inst_obj);
 
             if let Some(task_data) = servlet.servlet_context.async_init(handle, accessor)
             {
-                return Box::into_raw(task_data) as *mut c_void;
+                let task_obj = Box::new(AsyncTaskObject::<BT> {
+                    servlet_name: Arc::clone(&servlet.servlet_name),
+                    activation  : activation,
+                    task_data   : task_data
+                });
+                return Box::into_raw(task_obj) as *mut c_void;
             }
         }
     }
@@ -351,8 +420,9 @@ pub fn invoke_servlet_async_init<BT:Bootstrap>(obj_ptr : *mut c_void, handle_ptr
 pub fn invoke_servlet_async_exec<BT:Bootstrap>(handle_ptr : *mut c_void, task_data_ptr : *mut c_void) -> i32
 {
     let handle = unsafe { unpack_async_handle(handle_ptr) };
-    let task_data = unsafe { unpack_async_task_data::<BT>(task_data_ptr) };
-    if let Ok(_) = BT::AsyncServletType::async_exec(handle, task_data)
+    let task_obj = unsafe { unpack_async_task_data::<BT>(task_data_ptr) };
+    let _span = enter_activation("async_exec", &task_obj.servlet_name, task_obj.activation);
+    if let Ok(_) = BT::AsyncServletType::async_exec(handle, &mut task_obj.task_data)
     {
         return 0;
     }
@@ -382,10 +452,11 @@ pub fn invoke_servlet_async_cleanup<BT:Bootstrap>(obj_ptr : *mut c_void, handle_
         if let ServletObject::ASYNC(ref mut servlet) = unsafe { unpack_servlet_object::<BT>(obj_ptr) }
         {
             let handle = unsafe{ unpack_async_handle(handle_ptr) };
-            let task_data = unsafe { unpack_async_task_data::<BT>(task_data_ptr) };
+            let task_obj = unsafe { unpack_async_task_data::<BT>(task_data_ptr) };
+            let _span = enter_activation("async_cleanup", &task_obj.servlet_name, task_obj.activation);
             let accessor = <BT::AsyncServletType as AsyncServlet>::DataModelType::new_data_model(Rc::clone(&servlet.protocol_model), type_inst_obj);
 
-            if let Ok(_) = servlet.servlet_context.async_cleanup(handle, task_data, accessor)
+            if let Ok(_) = servlet.servlet_context.async_cleanup(handle, &mut task_obj.task_data, accessor)
             {
                 return 0;
             }
@@ -396,3 +467,108 @@ pub fn invoke_servlet_async_cleanup<BT:Bootstrap>(obj_ptr : *mut c_void, handle_
 
     return -1;
 }
+
+/**
+ * The object that wraps either a servlet object or an async task data object, for a shared object
+ * that exports multiple servlets. It carries the index of the bootstrap type that owns the wrapped
+ * object, so that the exported entry points are able to dispatch the calls.
+ *
+ * DO NOT use this type directly. It's designed to be used by the macro `export_servlets`.
+ **/
+pub struct MultiServletObject {
+    /// The index of the bootstrap type
+    index : usize,
+    /// The wrapped object
+    inner : *mut c_void
+}
+
+/**
+ * Select the servlet by the servlet name, which is the first servlet argument after the servlet
+ * binary.
+ *
+ * DO NOT use this function directly. It's designed to be used by the macro `export_servlets`.
+ *
+ * * `argc`: The number of servlet arguments
+ * * `argv`: The servlet arguments
+ * * `names`: The list of servlet names exported by the shared object
+ *
+ * Returns the index of the selected servlet and the servlet arguments without the servlet name,
+ * None if no servlet has been selected
+ **/
+pub unsafe fn select_servlet(argc: u32, argv: *const *const c_char, names: &[&str]) -> Option<(usize, Vec<*const c_char>)>
+{
+    if argc < 2
+    {
+        log_write(1, file!(), line!() as i32, &format!("Servlet name is required, available servlets: {}", names.join(", ")));
+        return None;
+    }
+
+    if let Ok(name) = CStr::from_ptr(*argv.offset(1)).to_str()
+    {
+        if let Some(index) = names.iter().position(|candidate| *candidate == name)
+        {
+            let mut args = vec![*argv];
+            for idx in 2..argc
+            {
+                args.push(*argv.offset(idx as isize));
+            }
+            return Some((index, args));
+        }
+
+        log_write(1, file!(), line!() as i32, &format!("Unknown servlet {}, available servlets: {}", name, names.join(", ")));
+    }
+
+    return None;
+}
+
+/**
+ * Wrap the object with the bootstrap type index
+ *
+ * DO NOT use this function directly. It's designed to be used by the macro `export_servlets`.
+ *
+ * * `index`: The index of the bootstrap type
+ * * `inner`: The object to wrap
+ *
+ * Returns the wrapped object, or NULL if the object to wrap is NULL
+ **/
+pub fn wrap_multi_object(index: usize, inner: *mut c_void) -> *mut c_void
+{
+    if inner.is_null()
+    {
+        return null::<c_void>() as *mut c_void;
+    }
+    return Box::into_raw(Box::new(MultiServletObject { index : index, inner : inner })) as *mut c_void;
+}
+
+/**
+ * Get the bootstrap type index and the wrapped object
+ *
+ * DO NOT use this function directly. It's designed to be used by the macro `export_servlets`.
+ *
+ * * `ptr`: The wrapped object
+ *
+ * Returns the bootstrap type index and the wrapped object
+ **/
+pub unsafe fn unwrap_multi_object(ptr: *mut c_void) -> Option<(usize, *mut c_void)>
+{
+    if let Some(obj) = (ptr as *const MultiServletObject).as_ref()
+    {
+        return Some((obj.index, obj.inner));
+    }
+    return None;
+}
+
+/**
+ * Dispose the wrapper object, the wrapped object isn't affected
+ *
+ * DO NOT use this function directly. It's designed to be used by the macro `export_servlets`.
+ *
+ * * `ptr`: The wrapped object
+ **/
+pub unsafe fn dispose_multi_object(ptr: *mut c_void)
+{
+    if !ptr.is_null()
+    {
+        dispose::<MultiServletObject>(ptr);
+    }
+}
@@ -9,6 +9,7 @@
 //! this type should be used in `export_bootstrap!` macro.
 
 use crate::protocol::{ProtocolModel, DataModel};
+use crate::metadata::PipeSchema;
 
 /**
  * The servlet function call result
@@ -248,6 +249,23 @@ pub trait Bootstrap where Self : Sized
      **/
     type SyncServletType : SyncServlet;
 
+    /**
+     * The description of the servlet, which is exported in the servlet metadata, see
+     * `plumber_rs::metadata` for details
+     **/
+    const DESCRIPTION : &'static str = "";
+
+    /**
+     * The version of the servlet, which is exported in the servlet metadata
+     **/
+    const VERSION : u32 = 0;
+
+    /**
+     * The schema of the servlet pipes, which is exported in the servlet metadata. It's usually
+     * the `SCHEMA` of the pipe set declared with `pipe_set!`
+     **/
+    const PIPES : &'static [PipeSchema] = &[];
+
     /**
      * Call the bootstrap object and get the actual servlet object for this servlet instance. 
      *
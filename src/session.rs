@@ -0,0 +1,292 @@
+// Copyright (C) 2018, Hao Hou
+
+//! The session abstraction for the persistent connections.
+//!
+//! A servlet that talks to a persistent connection, for example a TCP connection, is activated
+//! every time a piece of data arrives. For each activation, the servlet loads the session state
+//! attached to the input pipe, processes the available data, and then decides whether the
+//! connection should be kept or closed. The `Session` type does all of these except the data
+//! processing:
+//!
+//! ```rust
+//!     struct Servlet {
+//!         input   : SessionPipe<usize>,
+//!         output  : Pipe<Output>,
+//!         session : Session<usize>
+//!     }
+//!
+//!     // In Bootstrap::get
+//!     let session = Session::new(|| 0)
+//!         .with_idle_reset(Duration::from_secs(30))
+//!         .on_close(|count, reason| plumber_log!(I "{} lines, closed by {:?}", count, reason));
+//!
+//!     // In the exec function
+//!     let output = &mut self.output;
+//!     self.session.run(&mut self.input, |count, input| {
+//!         for line in BufReader::new(input).lines() {
+//!             *count += 1;
+//!             write!(output, "{} {}\n", count, line.map_err(|_| ())?).map_err(|_| ())?;
+//!         }
+//!         return Ok(SessionAction::Continue);
+//!     })?;
+//! ```
+//!
+//! The session can be reset after it has been idle for a while. This is not an idle timeout of the
+//! connection: the framework only activates the servlet when there's an event on the connection,
+//! so the idle time is only checked when the servlet is activated by the same connection again,
+//! and a connection that stays silent is kept until the framework releases it. A real idle timeout
+//! needs the framework to activate the servlet on a timer, which the servlet API doesn't provide,
+//! so closing the silent connections is left to the IO module that owns the connection. If the session has
+//! been idle for too long, it's closed with `CloseReason::IdleReset`, and the data that
+//! reactivated the servlet is processed by a new session on the same connection, so it's never
+//! dropped. If the connection is released by the framework without activating the servlet, the
+//! close callback is called with `CloseReason::Released` when the session state is disposed.
+
+use crate::pipe::{Pipe, PipeRef, Input};
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/**
+ * The reason why a session is closed
+ **/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// There's no more data from the connection
+    Eof,
+    /// The processing function asked to close the session
+    Requested,
+    /// The session hasn't been active for longer than the idle reset time, and the connection has
+    /// been reused by a new session
+    IdleReset,
+    /// The processing function or the pipe returned an error
+    Error,
+    /// The framework released the connection and disposed the session state
+    Released
+}
+
+/**
+ * The action the session should take after the available data has been processed
+ **/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionAction {
+    /// Keep the session as long as the connection may have more data
+    Continue,
+    /// Close the session
+    Close
+}
+
+/**
+ * The status of the session after the activation
+ **/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionStatus {
+    /// The session is kept for the next activation
+    Open,
+    /// The session has been closed
+    Closed
+}
+
+/**
+ * The close callback
+ **/
+type CloseCallback<S> = Arc<dyn Fn(S, CloseReason) + Send + Sync>;
+
+/**
+ * The session state attached to the input pipe. It's managed by `Session` and can't be accessed
+ * directly.
+ **/
+pub struct SessionData<S> {
+    /// The user defined state, None if the session has been closed
+    data        : Option<S>,
+    /// The last time the session was active
+    last_active : Instant,
+    /// The close callback
+    on_close    : Option<CloseCallback<S>>
+}
+
+impl <S> SessionData<S> {
+    /**
+     * Close the session and call the close callback
+     *
+     * * `reason`: The reason why the session is closed
+     **/
+    fn close(&mut self, reason:CloseReason)
+    {
+        if let Some(data) = self.data.take()
+        {
+            if let Some(ref on_close) = self.on_close
+            {
+                on_close(data, reason);
+            }
+        }
+    }
+}
+
+impl <S> Drop for SessionData<S> {
+    fn drop(&mut self)
+    {
+        self.close(CloseReason::Released);
+    }
+}
+
+/**
+ * The input pipe that carries a session state
+ **/
+pub type SessionPipe<S> = Pipe<Input, SessionData<S>>;
+
+/**
+ * The session helper, which loads the session state, runs the processing function and decides if
+ * the connection should be kept for each servlet activation.
+ *
+ * * `S`: The type of the user defined session state, which should be `Send`, because the close
+ * callback may be called from any worker thread
+ **/
+pub struct Session<S> {
+    /// The function that creates the state of a new session
+    init         : Box<dyn Fn() -> S + Send + Sync>,
+    /// The idle time after which the session is reset
+    idle_reset   : Option<Duration>,
+    /// The close callback
+    on_close     : Option<CloseCallback<S>>
+}
+
+impl <S:Send + 'static> Session<S> {
+    /**
+     * Create a new session helper
+     *
+     * * `init`: The function that creates the state of a new session
+     *
+     * Returns the newly created session helper
+     **/
+    pub fn new<F>(init:F) -> Session<S> where F : Fn() -> S + Send + Sync + 'static
+    {
+        return Session {
+            init         : Box::new(init),
+            idle_reset   : None,
+            on_close     : None
+        };
+    }
+
+    /**
+     * Reset the session when the connection becomes active again after it has been idle for
+     * longer than the given time. This doesn't close an idle connection, see the module
+     * documentation for details.
+     *
+     * * `idle`: The longest time the session can be inactive before it's reset
+     *
+     * Returns the session helper
+     **/
+    pub fn with_idle_reset(mut self, idle:Duration) -> Session<S>
+    {
+        self.idle_reset = Some(idle);
+        return self;
+    }
+
+    /**
+     * Set the function that is called when the session is closed. The function may be called
+     * from any worker thread, because the framework may dispose the session state anywhere.
+     *
+     * * `on_close`: The close callback, which takes the ownership of the session state
+     *
+     * Returns the session helper
+     **/
+    pub fn on_close<F>(mut self, on_close:F) -> Session<S> where F : Fn(S, CloseReason) + Send + Sync + 'static
+    {
+        self.on_close = Some(Arc::new(on_close));
+        return self;
+    }
+
+    /**
+     * Close the session attached to the pipe, and release the connection
+     *
+     * * `pipe`: The input pipe
+     * * `reason`: The reason why the session is closed
+     **/
+    fn close(&self, pipe:&mut SessionPipe<S>, reason:CloseReason)
+    {
+        if let Some(mut session) = pipe.take_state()
+        {
+            session.close(reason);
+        }
+    }
+
+    /**
+     * Run the session for the current activation.
+     *
+     * This should be called from the execution function. The processing function is called with
+     * the session state and the input pipe, and the session is closed when the processing function
+     * asks to, when it fails, or when the connection has no more data. If the session has been idle
+     * for longer than the idle reset time, it's closed before the processing function is called, and
+     * the processing function is called with the state of a new session.
+     *
+     * * `pipe`: The input pipe
+     * * `process`: The function that processes the available data
+     *
+     * Returns the status of the session, or an error if either the processing function or the
+     * pipe fails
+     **/
+    pub fn run<F>(&self, pipe:&mut SessionPipe<S>, mut process:F) -> Result<SessionStatus, ()>
+        where F : FnMut(&mut S, &mut PipeRef) -> Result<SessionAction, ()>
+    {
+        let now = Instant::now();
+
+        let mut input = pipe.as_pipe_ref();
+
+        let result = {
+            let init = &self.init;
+            let on_close = &self.on_close;
+
+            let mut session = match pipe.state_mut_or_insert_with(|| SessionData {
+                data        : None,
+                last_active : now,
+                on_close    : on_close.clone()
+            }) {
+                Some(session) => session,
+                None          => return Err(())
+            };
+
+            if let Some(idle) = self.idle_reset
+            {
+                if session.data.is_some() && now.duration_since(session.last_active) > idle
+                {
+                    session.close(CloseReason::IdleReset);
+                }
+            }
+
+            if session.data.is_none()
+            {
+                session.data = Some(init());
+            }
+
+            session.last_active = now;
+
+            match session.data {
+                Some(ref mut data) => process(data, &mut input),
+                None               => Err(())
+            }
+        };
+
+        match result {
+            Ok(SessionAction::Continue) => match pipe.eof() {
+                Some(false) => return Ok(SessionStatus::Open),
+                Some(true)  => {
+                    self.close(pipe, CloseReason::Eof);
+                    return Ok(SessionStatus::Closed);
+                },
+                None        => {
+                    self.close(pipe, CloseReason::Error);
+                    return Err(());
+                }
+            },
+            Ok(SessionAction::Close)    => {
+                self.close(pipe, CloseReason::Requested);
+                return Ok(SessionStatus::Closed);
+            },
+            Err(_)                      => {
+                self.close(pipe, CloseReason::Error);
+                return Err(());
+            }
+        }
+    }
+}
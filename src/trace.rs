@@ -0,0 +1,190 @@
+// Copyright (C) 2018, Hao Hou
+
+//! The `tracing` integration for the Rust servlet.
+//!
+//! This module is only available with the `tracing-layer` feature. It provides a
+//! `tracing_subscriber` layer that writes all the tracing events to the Plumber logging system,
+//! with the fields of the event and the enclosing spans rendered as `key=value` pairs.
+//!
+//! Once the layer is installed with `export_bootstrap!(Bootstrapper, tracing)`, the servlet glue
+//! code opens a span for each `exec`, `async_init`, `async_exec` and `async_cleanup` invocation,
+//! which carries the servlet name and the activation id. The servlet name is the name passed to
+//! `export_servlets!`, or the bootstrap type name if the crate exports a single servlet with
+//! `export_bootstrap!`. Thus all the events emitted during the activation can be followed in the
+//! Plumber log:
+//!
+//! ```text
+//!     exec{servlet=echo activation=42}: request received size=128
+//! ```
+
+use crate::log::log_write_func;
+
+use tracing_crate::{Event, Level, Subscriber};
+use tracing_crate::field::{Field, Visit};
+use tracing_crate::span::{Attributes, Id, Record};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::{LookupSpan, Registry};
+
+use std::fmt::{Debug, Write};
+
+/**
+ * The field visitor that renders all the fields as `key=value` pairs, except the message field,
+ * which is collected separately.
+ **/
+#[derive(Default)]
+struct FieldRenderer {
+    /// The message of the event
+    message : String,
+    /// The rendered fields
+    fields  : String
+}
+
+impl Visit for FieldRenderer {
+    fn record_str(&mut self, field:&Field, value:&str)
+    {
+        if field.name() == "message"
+        {
+            self.message.push_str(value);
+        }
+        else
+        {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field:&Field, value:&dyn Debug)
+    {
+        if field.name() == "message"
+        {
+            let _ = write!(self.message, "{:?}", value);
+        }
+        else
+        {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/**
+ * The rendered fields of a span, which is stored in the span extensions
+ **/
+struct SpanFields(String);
+
+/**
+ * The tracing layer that writes the events to the Plumber logging system.
+ *
+ * The tracing levels are mapped to the Plumber log levels as following: `ERROR` to error, `WARN`
+ * to warning, `INFO` to info, `DEBUG` to trace and `TRACE` to debug.
+ **/
+pub struct PlumberLayer;
+
+impl <S> Layer<S> for PlumberLayer where S : Subscriber + for<'a> LookupSpan<'a> {
+    fn on_new_span(&self, attrs:&Attributes, id:&Id, ctx:Context<S>)
+    {
+        if let Some(span) = ctx.span(id)
+        {
+            let mut renderer = FieldRenderer::default();
+            attrs.record(&mut renderer);
+            span.extensions_mut().insert(SpanFields(renderer.fields));
+        }
+    }
+
+    fn on_record(&self, id:&Id, values:&Record, ctx:Context<S>)
+    {
+        if let Some(span) = ctx.span(id)
+        {
+            let mut renderer = FieldRenderer::default();
+            values.record(&mut renderer);
+
+            let mut extensions = span.extensions_mut();
+            if let Some(fields) = extensions.get_mut::<SpanFields>()
+            {
+                fields.0.push_str(&renderer.fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event:&Event, ctx:Context<S>)
+    {
+        let metadata = event.metadata();
+
+        let level = match *metadata.level() {
+            Level::ERROR => 1,
+            Level::WARN  => 2,
+            Level::INFO  => 4,
+            Level::DEBUG => 5,
+            Level::TRACE => 6
+        };
+
+        let mut message = String::new();
+
+        if let Some(scope) = ctx.event_scope(event)
+        {
+            for span in scope.from_root()
+            {
+                message.push_str(span.name());
+                message.push('{');
+                if let Some(fields) = span.extensions().get::<SpanFields>()
+                {
+                    message.push_str(fields.0.trim_start());
+                }
+                message.push_str("}: ");
+            }
+        }
+
+        let mut renderer = FieldRenderer::default();
+        event.record(&mut renderer);
+
+        message.push_str(&renderer.message);
+        message.push_str(&renderer.fields);
+
+        log_write_func(level,
+                       metadata.file().unwrap_or("<unknown>"),
+                       metadata.module_path().unwrap_or(metadata.target()),
+                       metadata.line().unwrap_or(0) as i32,
+                       &message);
+    }
+}
+
+/**
+ * Install the Plumber tracing layer as the global tracing subscriber.
+ *
+ * *This function is called by `export_bootstrap!(Bootstrapper, tracing)`, and it's rarely needed
+ * to call it manually.*
+ *
+ * Returns if the subscriber has been installed, it fails when another global subscriber has been
+ * installed
+ **/
+pub fn install_subscriber() -> bool
+{
+    let subscriber = Registry::default().with(PlumberLayer);
+    return tracing_crate::subscriber::set_global_default(subscriber).is_ok();
+}
+
+/**
+ * The guard of an activation span, the span is exited when the guard gets dropped
+ **/
+pub type ActivationSpan = tracing_crate::span::EnteredSpan;
+
+/**
+ * Open and enter the span for a servlet activation.
+ *
+ * *This function is used by the servlet glue code, and it's rarely needed to call it manually.*
+ *
+ * * `kind`: The kind of the invocation, for example, `exec` or `async_init`
+ * * `servlet`: The name of the servlet
+ * * `activation`: The activation id
+ *
+ * Returns the guard of the span
+ **/
+pub fn enter_activation(kind:&str, servlet:&str, activation:usize) -> ActivationSpan
+{
+    let span = match kind {
+        "exec"          => tracing_crate::info_span!("exec", servlet = servlet, activation = activation),
+        "async_init"    => tracing_crate::info_span!("async_init", servlet = servlet, activation = activation),
+        "async_exec"    => tracing_crate::info_span!("async_exec", servlet = servlet, activation = activation),
+        "async_cleanup" => tracing_crate::info_span!("async_cleanup", servlet = servlet, activation = activation),
+        _               => tracing_crate::info_span!("activation", servlet = servlet, activation = activation)
+    };
+    return span.entered();
+}
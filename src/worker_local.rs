@@ -0,0 +1,154 @@
+// Copyright (C) 2018, Hao Hou
+
+//! The per-worker storage for the servlets.
+//!
+//! The `exec` function of a synchronous servlet may be called from any of the worker threads, so
+//! any cache owned by the servlet object needs to be synchronized. This module provides the
+//! binding to the libpstd thread local object, which gives each worker thread its own copy of the
+//! value, created by the initializer on the first access from that thread.
+//!
+//! Sample code:
+//! ```rust
+//!     struct Servlet {
+//!         buffer : WorkerLocal<Vec<u8>>
+//!     }
+//!
+//!     // In Bootstrap::get
+//!     let buffer = WorkerLocal::new(|_tid| Vec::with_capacity(4096))?;
+//!
+//!     // In the exec function
+//!     self.buffer.with(|buf| {
+//!         buf.clear();
+//!         // ...
+//!     });
+//! ```
+
+use crate::pstd::{
+    pstd_thread_local_t,
+    pstd_thread_local_new,
+    pstd_thread_local_get,
+    pstd_thread_local_free
+};
+
+use std::os::raw::{c_int, c_void};
+use std::cell::{RefCell, RefMut};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr::null_mut;
+
+/**
+ * The initializer of the thread local value, which takes the thread id
+ **/
+type Initializer<T> = Box<dyn Fn(u32) -> T + Send + Sync>;
+
+/**
+ * A value that each worker thread has its own copy of.
+ *
+ * The value for each thread is created by the initializer when the thread first accesses it, and
+ * all the values are dropped when the `WorkerLocal` object is dropped.
+ **/
+pub struct WorkerLocal<T> {
+    /// The libpstd thread local object
+    handle : *mut pstd_thread_local_t,
+    /// The initializer, it's boxed again so that the pointer passed to libpstd is stable
+    init   : *mut Initializer<T>
+}
+
+unsafe impl <T:Send> Send for WorkerLocal<T> {}
+unsafe impl <T:Send> Sync for WorkerLocal<T> {}
+
+unsafe extern "C" fn alloc_value<T>(tid:u32, data:*const c_void) -> *mut c_void
+{
+    if let Some(init) = (data as *const Initializer<T>).as_ref()
+    {
+        if let Ok(value) = catch_unwind(AssertUnwindSafe(|| init(tid)))
+        {
+            return Box::into_raw(Box::new(RefCell::new(value))) as *mut c_void;
+        }
+    }
+    return null_mut();
+}
+
+unsafe extern "C" fn dealloc_value<T>(mem:*mut c_void, _data:*const c_void) -> c_int
+{
+    if !mem.is_null()
+    {
+        drop(Box::from_raw(mem as *mut RefCell<T>));
+    }
+    return 0;
+}
+
+impl <T> WorkerLocal<T> {
+    /**
+     * Create a new worker local value.
+     *
+     * * `init`: The initializer, which is called with the thread id when a thread accesses the
+     * value for the first time
+     *
+     * Returns the newly created worker local or None on error
+     **/
+    pub fn new<F>(init:F) -> Option<WorkerLocal<T>> where F : Fn(u32) -> T + Send + Sync + 'static
+    {
+        let init:Initializer<T> = Box::new(init);
+        let init = Box::into_raw(Box::new(init));
+
+        let handle = unsafe { pstd_thread_local_new(Some(alloc_value::<T>), Some(dealloc_value::<T>), init as *const c_void) };
+
+        if handle.is_null()
+        {
+            drop(unsafe { Box::from_raw(init) });
+            return None;
+        }
+
+        return Some(WorkerLocal {
+            handle : handle,
+            init   : init
+        });
+    }
+
+    /**
+     * Get the value for current thread.
+     *
+     * The value is borrowed until the returned guard gets dropped.
+     *
+     * Returns the guard of the value, or None if the value can not be initialized or it's been
+     * borrowed by current thread already
+     **/
+    pub fn get<'a>(&'a self) -> Option<RefMut<'a, T>>
+    {
+        if let Some(cell) = unsafe { (pstd_thread_local_get(self.handle) as *const RefCell<T>).as_ref() }
+        {
+            if let Ok(value) = cell.try_borrow_mut()
+            {
+                return Some(value);
+            }
+        }
+        return None;
+    }
+
+    /**
+     * Run the function with the value for current thread
+     *
+     * * `f`: The function to run
+     *
+     * Returns the result of the function, or None if the value is not available, see `get` for
+     * details
+     **/
+    pub fn with<R, F>(&self, f:F) -> Option<R> where F : FnOnce(&mut T) -> R
+    {
+        if let Some(mut value) = self.get()
+        {
+            return Some(f(&mut value));
+        }
+        return None;
+    }
+}
+
+impl <T> Drop for WorkerLocal<T> {
+    fn drop(&mut self)
+    {
+        unsafe {
+            pstd_thread_local_free(self.handle);
+            drop(Box::from_raw(self.init));
+        }
+    }
+}